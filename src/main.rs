@@ -1,15 +1,19 @@
+mod error;
 pub mod models;
 pub mod services;
 mod utils;
 
+#[cfg(test)]
+mod test_support;
+
 use axum::{
     Router,
-    extract::{Multipart, State},
+    extract::{Multipart, Path, Query, State},
     http::StatusCode,
-    response::{Html, Json},
+    response::{Html, IntoResponse, Json},
     routing::{get, post},
 };
-use http;
+use error::ApiError;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tower_http::cors::CorsLayer;
@@ -20,36 +24,115 @@ use tracing_subscriber::{EnvFilter, fmt};
 #[derive(Clone)]
 struct AppState {
     llm_client: Arc<services::llm::LLMClient>,
+    jobs: Arc<services::jobs::JobRegistry>,
+    storage: Arc<dyn services::storage::Storage>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize tracing
-    fmt().with_env_filter(EnvFilter::from_default_env()).init();
+    // Initialize tracing, logging at info level unless RUST_LOG says otherwise
+    fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    // Fail fast if generated files can't be stored
+    let storage = match services::storage::from_env() {
+        Ok(storage) => storage,
+        Err(e) => {
+            tracing::error!("❌ {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Fail fast on option defaults that can't be used
+    if let Err(e) = models::ProcessOptions::check_env() {
+        tracing::error!("❌ {}", e);
+        std::process::exit(1);
+    }
 
     // Create the LLM client
     let llm_client = Arc::new(services::llm::LLMClient::new()?);
 
     // Create the application state
-    let app_state = AppState { llm_client };
+    let storage_location = storage.location();
+    let app_state = AppState {
+        llm_client,
+        jobs: Arc::new(services::jobs::JobRegistry::default()),
+        storage,
+    };
+
+    // Resolve the static directory once so it doesn't depend on later CWD changes
+    let static_dir = std::path::absolute(
+        std::env::var("STATIC_DIR").unwrap_or_else(|_| "static".to_string()),
+    )?;
+
+    let app = app(app_state, &static_dir);
+
+    // Print server location and available routes
+    let listener = TcpListener::bind("0.0.0.0:3000").await?;
+    let addr = listener.local_addr()?;
+    println!();
+    println!("🚀 Server running at: http://{}", addr);
+    println!();
+    println!("📋 Available Routes:");
+    println!("   GET  /               - Home page");
+    println!("   POST /upload         - Upload text file for chapterization");
+    println!("   POST /preview        - Detect chapters without LLM validation or output files");
+    println!("   POST /diff           - Compare chapter detection under two option sets");
+    println!("   GET  /health         - Health check endpoint");
+    println!("   GET  /metrics        - Requests served by each LLM endpoint");
+    println!("   GET  /download/:id   - Download generated EPUB file");
+    println!("   POST /revalidate/:id - Re-run LLM validation and rebuild an EPUB");
+    println!("   GET  /manifest/:id   - JSON manifest of a generated EPUB");
+    println!("   GET  /chapters/:id   - Chapters of a generated EPUB, paginated");
+    println!("   POST /jobs           - Upload text file for background chapterization");
+    println!("   GET  /jobs/:id       - Check the status of a background job");
+    println!("   DELETE /jobs/:id     - Cancel a background job");
+    println!("   GET  /static/*        - Static files");
+    println!();
+    println!("📁 Serving static files from: {}", static_dir.display());
+    println!("📁 Writing output files to: {}", storage_location);
+    println!();
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(utils::shutdown::signal())
+        .await?;
+
+    // Let any EPUB that was being generated finish before exiting
+    tracing::info!("Shutting down, waiting for in-progress writes...");
+    utils::shutdown::wait_for_writes().await;
+
+    Ok(())
+}
 
-    // Build our application with a route
-    let app = Router::new()
+/// Build the application: API routes plus static files from `static_dir`.
+fn app(app_state: AppState, static_dir: &std::path::Path) -> Router {
+    Router::new()
         .route("/", get(index))
         .route("/upload", post(upload_file))
+        .route("/preview", post(preview_file))
         .route("/health", get(health_check))
+        .route("/metrics", get(metrics))
+        .route("/diff", post(diff_file))
         .route("/download/:id", get(download_file))
-        .nest_service("/static", ServeDir::new("static"))
-        .fallback_service(ServeDir::new("static"))
+        .route("/revalidate/:id", post(revalidate_file))
+        .route("/manifest/:id", get(get_manifest))
+        .route("/chapters/:id", get(get_chapters))
+        .route("/jobs", post(create_job))
+        .route("/jobs/:id", get(get_job).delete(cancel_job))
+        // Unknown API paths get a JSON 404 instead of a static file lookup
+        .nest("/api", Router::new().fallback(api_not_found))
+        .nest_service("/static", ServeDir::new(static_dir))
+        .fallback_service(ServeDir::new(static_dir))
         .with_state(app_state)
         // Add request logging layer
         .layer(
             TraceLayer::new_for_http()
                 .on_request(|request: &http::Request<axum::body::Body>, _layer: &tracing::Span| {
-                    println!("Request: {} {}", request.method(), request.uri());
+                    tracing::info!("Request: {} {}", request.method(), request.uri());
                 })
                 .on_response(|response: &http::Response<axum::body::Body>, latency: std::time::Duration, _span: &tracing::Span| {
-                    println!("Response: {} in {}ms", response.status(), latency.as_millis());
+                    tracing::info!("Response: {} in {}ms", response.status(), latency.as_millis());
                 })
         )
         // Add CORS layer
@@ -58,25 +141,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .allow_origin(tower_http::cors::Any)
                 .allow_methods(tower_http::cors::AllowMethods::any())
                 .allow_headers(tower_http::cors::AllowHeaders::any()),
-        );
-
-    // Print server location and available routes
-    let listener = TcpListener::bind("0.0.0.0:3000").await?;
-    let addr = listener.local_addr()?;
-    println!();
-    println!("🚀 Server running at: http://{}", addr);
-    println!();
-    println!("📋 Available Routes:");
-    println!("   GET  /               - Home page");
-    println!("   POST /upload         - Upload text file for chapterization");
-    println!("   GET  /health         - Health check endpoint");
-    println!("   GET  /download/:id   - Download generated EPUB file");
-    println!("   GET  /static/*        - Static files");
-    println!();
-
-    axum::serve(listener, app).await?;
-
-    Ok(())
+        )
 }
 
 async fn index() -> Html<String> {
@@ -136,14 +201,50 @@ async fn index() -> Html<String> {
             <span class="method">GET</span>
             <strong>/health</strong> - Health check endpoint
         </div>
+        <div class="endpoint">
+            <span class="method">GET</span>
+            <strong>/metrics</strong> - Requests served by each LLM endpoint
+        </div>
         <div class="endpoint">
             <span class="method">POST</span>
             <strong>/upload</strong> - Upload text file for chapterization
         </div>
+        <div class="endpoint">
+            <span class="method">POST</span>
+            <strong>/preview</strong> - Detect chapters without LLM validation or output files
+        </div>
+        <div class="endpoint">
+            <span class="method">POST</span>
+            <strong>/diff</strong> - Compare chapter detection under two option sets
+        </div>
         <div class="endpoint">
             <span class="method">GET</span>
             <strong>/download/:id</strong> - Download generated EPUB file
         </div>
+        <div class="endpoint">
+            <span class="method">POST</span>
+            <strong>/revalidate/:id</strong> - Re-run LLM validation and rebuild an EPUB
+        </div>
+        <div class="endpoint">
+            <span class="method">GET</span>
+            <strong>/manifest/:id</strong> - JSON manifest of a generated EPUB
+        </div>
+        <div class="endpoint">
+            <span class="method">GET</span>
+            <strong>/chapters/:id</strong> - Chapters of a generated EPUB, paginated
+        </div>
+        <div class="endpoint">
+            <span class="method">POST</span>
+            <strong>/jobs</strong> - Upload text file for background chapterization
+        </div>
+        <div class="endpoint">
+            <span class="method">GET</span>
+            <strong>/jobs/:id</strong> - Check the status of a background job
+        </div>
+        <div class="endpoint">
+            <span class="method">DELETE</span>
+            <strong>/jobs/:id</strong> - Cancel a background job
+        </div>
         <div class="endpoint">
             <span class="method">GET</span>
             <strong>/static/*</strong> - Static files
@@ -167,72 +268,1412 @@ async fn index() -> Html<String> {
     "#.to_string())
 }
 
+async fn api_not_found(axum::extract::OriginalUri(uri): axum::extract::OriginalUri) -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, format!("No API endpoint at {}", uri.path()))
+}
+
 async fn health_check() -> &'static str {
     "OK"
 }
 
+/// Requests each LLM endpoint has served and failed since startup, which
+/// shows how often fallback endpoints are used.
+async fn metrics() -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "llm_endpoints": services::llm::endpoint_stats(),
+    }))
+}
+
+/// A parsed `/upload` request: the decoded file plus its options.
+struct Upload {
+    decoded: utils::text::DecodedText,
+    streamed_markers: Option<Vec<services::chapterizer::Marker>>,
+    options: models::ProcessOptions,
+    warnings: Vec<String>,
+}
+
+/// Map an error from processing an upload to its response.
+fn processing_error(e: anyhow::Error) -> ApiError {
+    tracing::error!("Error processing text: {:#}", e);
+    if e.is::<services::chapterizer::TimedOut>() {
+        return ApiError::new(StatusCode::GATEWAY_TIMEOUT, format!("{:#}", e));
+    }
+    if e.is::<services::chapterizer::UnexpectedChapterCount>() || e.is::<services::chapterizer::NoChaptersDetected>() {
+        return ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", e));
+    }
+    ApiError::internal(format!("Error processing text: {:#}", e))
+}
+
 async fn upload_file(
     State(state): State<AppState>,
-    mut multipart: Multipart,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    // Extract the uploaded text file
-    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
-        let name = field.name().unwrap_or("unknown");
+    multipart: Multipart,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let upload = read_upload(multipart).await?;
+    let llm_client = llm_client_for(&state, &upload.options)?;
+
+    let result = process_upload(&upload, &llm_client, &*state.storage, &Default::default())
+        .await
+        .map_err(processing_error)?;
+
+    let offsets = upload.options.original_positions.then_some(&upload.decoded.offsets);
+    let mut warnings = upload.warnings;
+    let inline = if upload.options.inline {
+        inline_epub(&result, &*state.storage, upload.options.inline_max_bytes, &mut warnings).await
+    } else {
+        None
+    };
+    let mut response = process_response(&result, offsets, warnings);
+    if let Some(epub) = inline {
+        response["epub_base64"] = epub.into();
+    }
+    Ok(Json(response))
+}
+
+/// The generated EPUB as base64, for clients that can't make a follow-up
+/// download request. EPUBs over `max_bytes` are left out with a warning.
+async fn inline_epub(
+    result: &models::ProcessResult,
+    storage: &dyn services::storage::Storage,
+    max_bytes: u64,
+    warnings: &mut Vec<String>,
+) -> Option<String> {
+    use base64::Engine;
+
+    if !result.formats.contains(&models::OutputFormat::Epub) {
+        warnings.push("'inline' was ignored because no EPUB was generated".to_string());
+        return None;
+    }
+    let name = services::chapterizer::output_name(&result.epub_id, models::OutputFormat::Epub);
+    let bytes = match storage.get(&name).await {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => {
+            tracing::error!("EPUB {} is missing; can't inline it", result.epub_id);
+            warnings.push("The EPUB couldn't be read to return it inline; download it instead".to_string());
+            return None;
+        }
+        Err(e) => {
+            tracing::error!("Failed to read EPUB {} for inlining: {:#}", result.epub_id, e);
+            warnings.push("The EPUB couldn't be read to return it inline; download it instead".to_string());
+            return None;
+        }
+    };
+    if bytes.len() as u64 > max_bytes {
+        warnings.push(format!(
+            "The EPUB is {} bytes, over the {} byte limit for inline responses; download it instead",
+            bytes.len(),
+            max_bytes
+        ));
+        return None;
+    }
+    Some(base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+/// Run chapter detection on an upload without LLM validation or output
+/// files, reporting how many lines each heading pattern matched.
+async fn preview_file(multipart: Multipart) -> Result<Json<serde_json::Value>, ApiError> {
+    let mut upload = read_upload(multipart).await?;
+    let preview = services::chapterizer::preview(
+        &upload.decoded.text,
+        upload.streamed_markers.as_deref(),
+        &upload.options,
+    )
+    .map_err(ApiError::bad_request)?;
+
+    let offsets = upload.options.original_positions.then_some(&upload.decoded.offsets);
+    upload.warnings.extend(preview.warnings);
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "chapter_count": preview.chapters.len(),
+        "chapters": chapters_json(&preview.chapters, offsets),
+        "pattern_stats": preview.pattern_stats,
+        "warnings": upload.warnings,
+    })))
+}
+
+/// Run detection twice over one text with two option sets and report how
+/// the chapters differ. Fields prefixed `a.` or `b.` apply to one run only;
+/// other option fields apply to both.
+async fn diff_file(mut multipart: Multipart) -> Result<Json<serde_json::Value>, ApiError> {
+    let mut data: Option<Vec<u8>> = None;
+    let mut options = [models::ProcessOptions::from_env(), models::ProcessOptions::from_env()];
+    let mut warnings: Vec<String> = Vec::new();
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("Invalid multipart body: {}", e)))?
+    {
+        let name = field.name().unwrap_or("unknown").to_string();
         if name == "text_file" {
-            let data = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+            if data.is_some() {
+                return Err(ApiError::bad_request(
+                    "Multiple files were uploaded in 'text_file'; only one file per upload is supported",
+                ));
+            }
+            let bytes = field
+                .bytes()
+                .await
+                .map_err(|e| ApiError::bad_request(format!("Failed to read uploaded file: {}", e)))?;
+            data = Some(bytes.to_vec());
+            continue;
+        }
 
-            // Handle potential BOM (Byte Order Mark) in UTF-8 files
-            let text_bytes = data.to_vec();
-            let text_content = if text_bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
-                // Skip the UTF-8 BOM if present
-                String::from_utf8(text_bytes[3..].to_vec()).map_err(|_| StatusCode::BAD_REQUEST)?
-            } else {
-                String::from_utf8(text_bytes).map_err(|_| StatusCode::BAD_REQUEST)?
-            };
+        let value = read_text_field(field, &name).await?;
+        let (runs, option) = match name.split_once('.') {
+            Some(("a", option)) => (&mut options[..1], option),
+            Some(("b", option)) => (&mut options[1..], option),
+            _ => (&mut options[..], name.as_str()),
+        };
+        let mut recognized = false;
+        for run in runs {
+            recognized |= run.set(option, &value).map_err(ApiError::bad_request)?;
+        }
+        if !recognized {
+            warnings.push(format!("Ignored unknown field '{}'", name));
+        }
+    }
+
+    let data = data.ok_or_else(|| ApiError::bad_request("Missing 'text_file' field"))?;
+    let mut runs = Vec::new();
+    for (label, options) in ["a", "b"].into_iter().zip(&options) {
+        let mut run_warnings = Vec::new();
+        let decoded = decode_upload(&data, options, &mut run_warnings)?;
+        let (decoded, markers) = convert_html(&decoded, options, &mut run_warnings).unwrap_or((decoded, None));
+        let preview = services::chapterizer::preview(&decoded.text, markers.as_deref(), options)
+            .map_err(|e| ApiError::bad_request(format!("Run '{}': {}", label, e)))?;
+        run_warnings.extend(preview.warnings.iter().cloned());
+        runs.push((preview, run_warnings));
+    }
+
+    let diff = services::chapterizer::diff_chapters(&runs[0].0.chapters, &runs[1].0.chapters);
+    let run_json = |(preview, warnings): &(models::PreviewResult, Vec<String>)| {
+        serde_json::json!({
+            "chapter_count": preview.chapters.len(),
+            "chapters": chapters_json(&preview.chapters, None),
+            "warnings": warnings,
+        })
+    };
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "a": run_json(&runs[0]),
+        "b": run_json(&runs[1]),
+        "diff": diff,
+        "warnings": warnings,
+    })))
+}
+
+/// Start processing an upload in the background and return its job ID.
+async fn create_job(
+    State(state): State<AppState>,
+    multipart: Multipart,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    let upload = read_upload(multipart).await?;
+    let llm_client = llm_client_for(&state, &upload.options)?;
+
+    let (job_id, job) = state.jobs.start();
+
+    let jobs = state.jobs.clone();
+    let storage = state.storage.clone();
+    let id = job_id.clone();
+    tokio::spawn(async move {
+        let outcome = match process_upload(&upload, &llm_client, &*storage, &job).await {
+            Ok(result) => {
+                // Cancelled after the last check; don't keep the output around
+                if job.cancel.is_cancelled() {
+                    services::chapterizer::remove_outputs(&result.epub_id, &*storage).await;
+                }
+                let offsets = upload.options.original_positions.then_some(&upload.decoded.offsets);
+                Ok(process_response(&result, offsets, upload.warnings))
+            }
+            Err(e) => {
+                if !e.is::<services::chapterizer::Cancelled>() {
+                    tracing::error!("Error processing job {}: {:#}", id, e);
+                }
+                Err(format!("Error processing text: {:#}", e))
+            }
+        };
+        jobs.finish(&id, outcome);
+    });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "success": true,
+            "job_id": job_id,
+            "status_url": format!("/jobs/{}", job_id),
+        })),
+    ))
+}
+
+async fn get_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let job = state
+        .jobs
+        .get(&id)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Unknown job id"))?;
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "job_id": id,
+        "status": job.status,
+        "progress": job.progress,
+        "result": job.result,
+        "error": job.error,
+    })))
+}
+
+/// Cancel a running job. Its output is removed once processing stops.
+async fn cancel_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let status = state
+        .jobs
+        .cancel(&id)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Unknown job id"))?;
+
+    if status != services::jobs::JobStatus::Cancelled {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Job has already finished and can't be cancelled",
+        ));
+    }
 
-            // Process the text content into chapters
-            let result = services::chapterizer::process_text(&text_content, &state.llm_client)
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "job_id": id,
+        "status": status,
+    })))
+}
+
+/// Read the multipart fields of an upload and decode the text file.
+async fn read_upload(mut multipart: Multipart) -> Result<Upload, ApiError> {
+    let mut upload: Option<(Vec<u8>, Option<StreamedMarkers>)> = None;
+    let mut options = models::ProcessOptions::from_env();
+    let mut streamed_detection = options.detection();
+    let mut warnings: Vec<String> = Vec::new();
+
+    // Collect the uploaded text file and any per-request options
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("Invalid multipart body: {}", e)))?
+    {
+        let name = field.name().unwrap_or("unknown").to_string();
+        if name == "text_file" {
+            // Only one file can be chapterized per upload
+            if upload.is_some() {
+                return Err(ApiError::bad_request(
+                    "Multiple files were uploaded in 'text_file'; only one file per upload is supported",
+                ));
+            }
+            // Detect with the options sent so far; later fields may change them
+            streamed_detection = options.detection();
+            options.epub.source_filename = field.file_name().map(str::to_string);
+            upload = Some(read_text_file(field, &streamed_detection).await?);
+        } else {
+            let value = read_text_field(field, &name).await?;
+            let recognized = options.set(&name, &value).map_err(ApiError::bad_request)?;
+            if !recognized {
+                warnings.push(format!("Ignored unknown field '{}'", name));
+            }
+        }
+    }
+
+    let (data, streamed) = upload.ok_or_else(|| ApiError::bad_request("Missing 'text_file' field"))?;
+    let decoded = decode_upload(&data, &options, &mut warnings)?;
+
+    let (decoded, streamed_markers) = match convert_html(&decoded, &options, &mut warnings) {
+        Some(converted) => converted,
+        None => {
+            // Only trust markers found while streaming if they were found in
+            // exactly this text and no later field changed detection
+            let streamed_markers = streamed
+                .filter(|streamed| {
+                    streamed.len == decoded.text.len()
+                        && options.detection() == streamed_detection
+                })
+                .map(|streamed| streamed.markers);
+            (decoded, streamed_markers)
+        }
+    };
+
+    // Explicit split points replace detection entirely
+    let streamed_markers = match &options.line_splits {
+        Some(lines) => Some(
+            services::chapterizer::markers_at_lines(&decoded.text, lines)
+                .map_err(ApiError::bad_request)?,
+        ),
+        None => streamed_markers,
+    };
+
+    Ok(Upload {
+        decoded,
+        streamed_markers,
+        options,
+        warnings,
+    })
+}
+
+/// Decode an uploaded file as UTF-8 (lossily when `lossy_utf8` is set) and
+/// reject it if it doesn't look like text.
+fn decode_upload(
+    data: &[u8],
+    options: &models::ProcessOptions,
+    warnings: &mut Vec<String>,
+) -> Result<utils::text::DecodedText, ApiError> {
+    // Decoding waits for all fields so `lossy_utf8` may come after the file
+    let decoded = match utils::decode_text(data) {
+        Ok(decoded) => decoded,
+        Err(_) if options.lossy_utf8 => {
+            let (decoded, replaced) = utils::decode_text_lossy(data);
+            warnings.push(format!(
+                "Uploaded file is not valid UTF-8; replaced {} invalid byte sequence(s) with U+FFFD",
+                replaced
+            ));
+            decoded
+        }
+        Err(_) => return Err(ApiError::bad_request("Uploaded file is not valid UTF-8")),
+    };
+
+    // Binary files and text decoded with the wrong encoding are mostly
+    // control and replacement characters
+    if let Some(min_ratio) = options.min_printable_ratio {
+        let ratio = utils::text::printable_ratio(&decoded.text);
+        if ratio < min_ratio {
+            return Err(ApiError::bad_request(format!(
+                "Uploaded file doesn't look like text: only {:.1}% of its characters are printable (at least {:.1}% required)",
+                ratio * 100.0,
+                min_ratio * 100.0
+            )));
+        }
+    }
+    Ok(decoded)
+}
+
+/// Reduce HTML input to its text, with chapters starting at its top-level
+/// headings (`None` for markers when it has none). Positions then refer to
+/// the extracted text. Returns `None` for input that isn't HTML.
+fn convert_html(
+    decoded: &utils::text::DecodedText,
+    options: &models::ProcessOptions,
+    warnings: &mut Vec<String>,
+) -> Option<(utils::text::DecodedText, Option<Vec<services::chapterizer::Marker>>)> {
+    let is_html = match options.input_format {
+        models::InputFormat::Auto => utils::html::looks_like_html(&decoded.text),
+        models::InputFormat::Text => false,
+        models::InputFormat::Html => true,
+    };
+    if !is_html {
+        return None;
+    }
+    let html = utils::html::html_to_text(&decoded.text);
+    warnings.push(format!(
+        "Converted HTML input to text; found {} <h1>/<h2> heading(s)",
+        html.heading_lines.len()
+    ));
+    let markers = Some(&html.heading_lines)
+        .filter(|lines| !lines.is_empty())
+        .and_then(|lines| services::chapterizer::markers_at_lines(&html.text, lines).ok());
+    let decoded = utils::text::DecodedText {
+        text: html.text,
+        offsets: Default::default(),
+    };
+    Some((decoded, markers))
+}
+
+/// Process the text content into chapters, reusing the markers found while
+/// the upload streamed in when available.
+async fn process_upload(
+    upload: &Upload,
+    llm_client: &Arc<services::llm::LLMClient>,
+    storage: &dyn services::storage::Storage,
+    job: &services::jobs::JobHandle,
+) -> anyhow::Result<models::ProcessResult> {
+    let text = &upload.decoded.text;
+    let options = &upload.options;
+    match &upload.streamed_markers {
+        Some(markers) => {
+            services::chapterizer::process_text_with_markers(text, markers, llm_client, storage, options, job)
                 .await
-                .map_err(|e| {
-                    eprintln!("Error processing text: {}", e);
-                    StatusCode::INTERNAL_SERVER_ERROR
-                })?;
+        }
+        None => services::chapterizer::process_text(text, llm_client, storage, options, job).await,
+    }
+}
+
+/// Pick the LLM client for a request, building a per-request one when the
+/// caller overrides the LLM backend.
+fn llm_client_for(
+    state: &AppState,
+    options: &models::ProcessOptions,
+) -> Result<Arc<services::llm::LLMClient>, ApiError> {
+    if options.llm_url.is_none() && options.llm_model.is_none() && options.llm_seed.is_none() {
+        return Ok(state.llm_client.clone());
+    }
+
+    let client = state
+        .llm_client
+        .with_overrides(
+            options.llm_url.as_deref(),
+            options.llm_model.as_deref(),
+            options.llm_seed,
+        )
+        .map_err(|e| ApiError::bad_request(format!("Rejected LLM override: {}", e)))?;
+    Ok(Arc::new(client))
+}
+
+/// Describe chapters for a JSON response. When `offsets` is given, chapter
+/// positions are mapped back to the original upload bytes.
+fn chapters_json(
+    chapters: &[models::Chapter],
+    offsets: Option<&utils::text::OffsetMap>,
+) -> Vec<serde_json::Value> {
+    chapters
+        .iter()
+        .map(|chapter| {
+            let (start_pos, end_pos) = match offsets {
+                Some(offsets) => (
+                    offsets.original_offset(chapter.start_pos),
+                    offsets.original_offset(chapter.end_pos),
+                ),
+                None => (chapter.start_pos, chapter.end_pos),
+            };
+            serde_json::json!({
+                "title": chapter.title,
+                "role": chapter.role,
+                "start_pos": start_pos,
+                "end_pos": end_pos,
+                "byte_length": chapter.byte_length(),
+                "char_length": chapter.char_length(),
+            })
+        })
+        .collect()
+}
+
+/// Build the JSON body describing a processed EPUB. When `offsets` is given,
+/// chapter positions are mapped back to the original upload bytes. Request
+/// warnings are reported ahead of the ones from processing.
+fn process_response(
+    result: &models::ProcessResult,
+    offsets: Option<&utils::text::OffsetMap>,
+    mut warnings: Vec<String>,
+) -> serde_json::Value {
+    warnings.extend(result.warnings.iter().cloned());
+    let chapters = chapters_json(&result.chapters, offsets);
+
+    // One download link per generated format; `download_url` keeps pointing
+    // at the primary (first requested) format for existing clients
+    let download_urls: serde_json::Map<String, serde_json::Value> = result
+        .formats
+        .iter()
+        .map(|format| {
+            let url = match format {
+                models::OutputFormat::Epub => format!("/download/{}", result.epub_id),
+                _ => format!("/download/{}?format={}", result.epub_id, format.name()),
+            };
+            (format.name().to_string(), serde_json::Value::String(url))
+        })
+        .collect();
+    let download_url = result
+        .formats
+        .first()
+        .and_then(|format| download_urls.get(format.name()).cloned())
+        .unwrap_or_else(|| format!("/download/{}", result.epub_id).into());
+
+    serde_json::json!({
+        "success": true,
+        "chapter_count": result.chapters.len(),
+        "chapters": chapters,
+        "download_url": download_url,
+        "download_urls": download_urls,
+        "warnings": warnings
+    })
+}
 
-            return Ok(Json(serde_json::json!({
-                "success": true,
-                "chapter_count": result.chapters.len(),
-                "download_url": format!("/download/{}", result.epub_id)
-            })));
+/// Re-run LLM validation for a previously generated EPUB, optionally with
+/// different LLM or EPUB settings, and rebuild it under the same ID.
+async fn revalidate_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    multipart: Option<Multipart>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    // IDs are always UUIDs; anything else can't name a stored EPUB
+    if uuid::Uuid::parse_str(&id).is_err() {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "Unknown EPUB id"));
+    }
+
+    let sidecar = services::chapterizer::load_sidecar(&id, &*state.storage)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to load chapters: {}", e)))?
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Unknown EPUB id"))?;
+
+    // Start from the settings the EPUB was built with
+    let mut options = models::ProcessOptions {
+        epub: sidecar.epub.clone(),
+        formats: sidecar.formats.clone(),
+        ..models::ProcessOptions::from_env()
+    };
+    let mut warnings: Vec<String> = Vec::new();
+
+    if let Some(mut multipart) = multipart {
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|e| ApiError::bad_request(format!("Invalid multipart body: {}", e)))?
+        {
+            let name = field.name().unwrap_or("unknown").to_string();
+            let value = read_text_field(field, &name).await?;
+            let recognized = options.set(&name, &value).map_err(ApiError::bad_request)?;
+            if !recognized {
+                warnings.push(format!("Ignored unknown field '{}'", name));
+            }
+        }
+    }
+
+    let llm_client = llm_client_for(&state, &options)?;
+
+    let result = services::chapterizer::revalidate(&id, sidecar, &llm_client, &*state.storage, &options)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error revalidating {}: {:#}", id, e);
+            ApiError::internal(format!("Error revalidating: {:#}", e))
+        })?;
+
+    Ok(Json(process_response(&result, None, warnings)))
+}
+
+/// Read a non-file form field, decoding it the same way as the uploaded text
+/// (UTF-8 with an optional BOM) so sidecar and option fields behave the same.
+async fn read_text_field(
+    field: axum::extract::multipart::Field<'_>,
+    name: &str,
+) -> Result<String, ApiError> {
+    let data = field
+        .bytes()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("Failed to read field '{}': {}", name, e)))?;
+    utils::decode_text(&data)
+        .map(|decoded| decoded.text)
+        .map_err(|_| ApiError::bad_request(format!("Field '{}' is not valid UTF-8", name)))
+}
+
+/// Chapter markers found while an upload streamed in, with the length of the
+/// text they were found in.
+struct StreamedMarkers {
+    len: usize,
+    markers: Vec<services::chapterizer::Marker>,
+}
+
+/// Read the uploaded file, running chapter detection on it as it streams in.
+/// Markers are only returned if the whole stream decoded as UTF-8, and never
+/// with `parallel_detection`, which detects once the whole text is in.
+async fn read_text_file(
+    mut field: axum::extract::multipart::Field<'_>,
+    detection: &models::DetectionSettings,
+) -> Result<(Vec<u8>, Option<StreamedMarkers>), ApiError> {
+    let mut data = Vec::new();
+    let mut decoder = (!detection.parallel).then(utils::text::Utf8ChunkDecoder::default);
+    let mut detector = services::chapterizer::ChapterDetector::for_settings(detection);
+    let mut streamed_len = 0;
+
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|e| ApiError::bad_request(format!("Failed to read uploaded file: {}", e)))?
+    {
+        data.extend_from_slice(&chunk);
+        match decoder.as_mut().and_then(|d| d.push(&chunk)) {
+            Some(text) => {
+                streamed_len += text.len();
+                detector.feed(&text);
+            }
+            None => decoder = None,
+        }
+    }
+
+    let streamed_ok = match decoder.and_then(|d| d.finish()) {
+        Some(text) => {
+            streamed_len += text.len();
+            detector.feed(&text);
+            true
         }
+        None => false,
+    };
+
+    let streamed = streamed_ok.then(|| StreamedMarkers {
+        len: streamed_len,
+        markers: detector.finish(),
+    });
+    Ok((data, streamed))
+}
+
+
+/// Serve the manifest written for an EPUB uploaded with `manifest=true`.
+async fn get_manifest(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<axum::response::Response, ApiError> {
+    // IDs are always UUIDs; anything else can't name a stored manifest
+    if uuid::Uuid::parse_str(&id).is_err() {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "Unknown EPUB id"));
     }
 
-    Err(StatusCode::BAD_REQUEST)
+    let manifest = match state.storage.get(&services::chapterizer::manifest_name(&id)).await {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "No manifest for this EPUB; upload with manifest=true to create one",
+            ));
+        }
+        Err(e) => return Err(ApiError::internal(format!("Failed to read manifest: {:#}", e))),
+    };
+
+    axum::response::Response::builder()
+        .header("Content-Type", "application/json")
+        .body(axum::body::Body::from(manifest))
+        .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))
 }
 
-use axum::extract::Path;
+#[derive(serde::Deserialize)]
+struct ChaptersQuery {
+    /// Chapters to skip from the start of the book
+    #[serde(default)]
+    offset: usize,
+    /// Most chapters to return (default: all after `offset`)
+    limit: Option<usize>,
+    /// Also return each chapter's text
+    #[serde(default = "default_include_content")]
+    include_content: bool,
+}
 
-async fn download_file(Path(id): Path<String>) -> Result<axum::response::Response, StatusCode> {
-    use std::fs;
-    use std::path::Path as StdPath;
+fn default_include_content() -> bool {
+    true
+}
 
-    // Construct the file path
-    let file_path = format!("./output/{}.epub", id);
+/// List the chapters of a generated EPUB from its sidecar, a page at a time
+/// for large books. With `include_content=false`, only titles and other
+/// metadata are returned so clients can fetch text on demand.
+async fn get_chapters(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<ChaptersQuery>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    // IDs are always UUIDs; anything else can't name a stored sidecar
+    if uuid::Uuid::parse_str(&id).is_err() {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "Unknown EPUB id"));
+    }
+
+    let sidecar = match services::chapterizer::load_sidecar(&id, &*state.storage).await {
+        Ok(Some(sidecar)) => sidecar,
+        Ok(None) => return Err(ApiError::new(StatusCode::NOT_FOUND, "Unknown EPUB id")),
+        Err(e) => return Err(ApiError::internal(format!("Failed to read chapters: {:#}", e))),
+    };
+
+    let total = sidecar.chapters.len();
+    let page: Vec<models::Chapter> = sidecar
+        .chapters
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit.unwrap_or(usize::MAX))
+        .collect();
+    let mut chapters = chapters_json(&page, None);
+    if query.include_content {
+        for (json, chapter) in chapters.iter_mut().zip(&page) {
+            json["content"] = serde_json::Value::String(chapter.content.clone());
+        }
+    }
 
-    // Check if the file exists
-    if !StdPath::new(&file_path).exists() {
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "total": total,
+        "offset": query.offset,
+        "chapters": chapters,
+    })))
+}
+
+#[derive(serde::Deserialize)]
+struct DownloadQuery {
+    format: Option<String>,
+}
+
+async fn download_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<axum::response::Response, StatusCode> {
+    // IDs are always UUIDs; anything else can't name a stored file
+    if uuid::Uuid::parse_str(&id).is_err() {
         return Err(StatusCode::NOT_FOUND);
     }
 
-    // Read the file content
-    let file_content = fs::read(&file_path).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let format = match query.format.as_deref() {
+        None => models::OutputFormat::Epub,
+        Some(value) => models::OutputFormat::parse(value).ok_or(StatusCode::BAD_REQUEST)?,
+    };
 
-    // Create a response with the file content
-    Ok(axum::response::Response::builder()
-        .header("Content-Type", "application/epub+zip")
+    // A missing file means an unknown ID
+    let name = services::chapterizer::output_name(&id, format);
+
+    // Backends that serve files themselves get the client redirected there
+    let file_name = format!("{}.{}", id, format.extension());
+    if let Some(url) = state.storage.download_url(&name, &file_name) {
+        return match state.storage.exists(&name).await {
+            Ok(true) => Ok(axum::response::Redirect::temporary(&url).into_response()),
+            Ok(false) => Err(StatusCode::NOT_FOUND),
+            Err(e) => {
+                tracing::error!("Failed to check for {}: {:#}", name, e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+    }
+
+    let file = match state.storage.get_stream(&name).await {
+        Ok(Some(file)) => file,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to read {}: {:#}", name, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    axum::response::Response::builder()
+        .header("Content-Type", format.content_type())
+        .header("Content-Length", file.len)
         .header(
             "Content-Disposition",
-            format!("attachment; filename=\"{}.epub\"", id),
+            format!("attachment; filename=\"{}\"", file_name),
         )
-        .body(axum::body::Body::from(file_content))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?)
+        .body(axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(file.reader)))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::storage::Storage;
+    use crate::test_support::{Form, MemoryStorage, MockServer};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn state(llm: &MockServer, storage: Arc<dyn Storage>) -> AppState {
+        let url = format!("{}/api/generate", llm.url);
+        let llm_client = services::llm::LLMClient::with_endpoints(vec![url], "", None, services::llm::ApiFormat::Ollama)
+            .unwrap();
+        AppState {
+            llm_client: Arc::new(llm_client),
+            jobs: Arc::new(services::jobs::JobRegistry::default()),
+            storage,
+        }
+    }
+
+    // Serve `app` on a local port, returning its base URL
+    async fn serve_app(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        base
+    }
+
+    // Serve the API with an LLM backend at `llm` and no static files
+    async fn serve(llm: &MockServer, storage: Arc<dyn Storage>) -> String {
+        serve_app(app(state(llm, storage), &std::env::temp_dir().join("duanzh-no-static-dir"))).await
+    }
+
+    #[tokio::test]
+    async fn uploading_two_files_is_rejected() {
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let storage = Arc::new(MemoryStorage::default());
+        let base = serve(&llm, storage.clone()).await;
+
+        let response = Form::default()
+            .file("text_file", "one.txt", b"Chapter 1\n\nOne.\n")
+            .file("text_file", "two.txt", b"Chapter 1\n\nTwo.\n")
+            .post(&format!("{}/upload", base))
+            .await;
+        assert_eq!(response.status(), 400);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["success"], false);
+        assert!(body["error"].as_str().unwrap().starts_with("Multiple files were uploaded in 'text_file'"));
+        assert!(llm.requests().is_empty());
+        assert!(storage.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn health_checks_are_answered_while_a_large_epub_is_generated() {
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let base = serve(&llm, Arc::new(MemoryStorage::default())).await;
+        let book: String = (1..=3000)
+            .map(|n| format!("Chapter {}\n\n{}\n\n", n, "Some text for this chapter. ".repeat(20)))
+            .collect();
+
+        let form = Form::default()
+            .text("strictness", "low")
+            .file("text_file", "book.txt", book.as_bytes());
+        let url = format!("{}/upload", base);
+        let upload = tokio::spawn(async move { form.post(&url).await });
+        // Only checks made while the EPUB is being built and written count
+        let client = reqwest::Client::new();
+        let mut slowest = std::time::Duration::ZERO;
+        let mut checks = 0;
+        while !upload.is_finished() {
+            let generating = utils::shutdown::in_flight_writes() > 0;
+            let started = std::time::Instant::now();
+            let response = client.get(format!("{}/health", base)).send().await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "OK");
+            if generating {
+                slowest = slowest.max(started.elapsed());
+                checks += 1;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let response = upload.await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["chapter_count"], 3000);
+        assert!(checks >= 3, "only {} health checks ran while the EPUB was generated", checks);
+        assert!(slowest < std::time::Duration::from_millis(200), "a health check took {:?}", slowest);
+    }
+
+    #[tokio::test]
+    async fn option_fields_with_a_byte_order_mark_are_parsed() {
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let base = serve(&llm, Arc::new(MemoryStorage::default())).await;
+        let text = format!("Scene 1\n\n{0}\n\nScene 2\n\n{0}\n\nTHE END\n\nScene 3\n\n{0}\n", "Some text. ".repeat(20));
+
+        let response = Form::default()
+            .text("keywords", "\u{FEFF}Scene")
+            .text("stop_patterns", "\u{FEFF}^THE END$")
+            .file("text_file", "play.txt", text.as_bytes())
+            .post(&format!("{}/preview", base))
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let titles: Vec<&str> = body["chapters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|chapter| chapter["title"].as_str().unwrap())
+            .collect();
+        assert_eq!(titles, ["Scene 1", "Scene 2"], "{}", body);
+    }
+
+    #[tokio::test]
+    async fn requesting_two_formats_stores_both_and_returns_both_urls() {
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let storage = Arc::new(MemoryStorage::default());
+        let base = serve(&llm, storage.clone()).await;
+        let text = format!("Chapter 1\n\n{0}\n\nChapter 2\n\n{0}\n", "Some text. ".repeat(20));
+
+        let response = Form::default()
+            .text("strictness", "low")
+            .text("formats", "epub,markdown")
+            .file("text_file", "book.txt", text.as_bytes())
+            .post(&format!("{}/upload", base))
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let epub_url = body["download_urls"]["epub"].as_str().unwrap();
+        let markdown_url = body["download_urls"]["markdown"].as_str().unwrap();
+        assert_eq!(body["download_url"], epub_url);
+        let id = epub_url.trim_start_matches("/download/");
+        assert_eq!(markdown_url, format!("/download/{}?format=markdown", id));
+
+        let mut files = storage.list().await.unwrap();
+        files.retain(|name| !name.ends_with(".json"));
+        files.sort();
+        assert_eq!(files, [format!("{}.epub", id), format!("{}.md", id)]);
+
+        let epub = reqwest::get(format!("{}{}", base, epub_url)).await.unwrap();
+        assert_eq!(epub.headers()["content-type"], "application/epub+zip");
+        let markdown = reqwest::get(format!("{}{}", base, markdown_url)).await.unwrap().text().await.unwrap();
+        assert!(markdown.contains("# Chapter 1") && markdown.contains("# Chapter 2"), "{}", markdown);
+    }
+
+    #[tokio::test]
+    async fn a_stray_invalid_byte_is_replaced_with_a_warning_in_lossy_mode() {
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let base = serve(&llm, Arc::new(MemoryStorage::default())).await;
+        let mut text = format!("Chapter 1\n\n{0}\n\nChapter 2\n\n{0}\n", "Some text. ".repeat(20)).into_bytes();
+        text.insert(20, 0xFF);
+
+        let response = Form::default()
+            .file("text_file", "book.txt", &text)
+            .post(&format!("{}/preview", base))
+            .await;
+        assert_eq!(response.status(), 400);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"], "Uploaded file is not valid UTF-8");
+
+        let response = Form::default()
+            .file("text_file", "book.txt", &text)
+            .text("lossy_utf8", "true")
+            .post(&format!("{}/preview", base))
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["chapter_count"], 2);
+        assert_eq!(
+            body["warnings"][0],
+            "Uploaded file is not valid UTF-8; replaced 1 invalid byte sequence(s) with U+FFFD"
+        );
+    }
+
+    #[tokio::test]
+    async fn static_files_are_served_from_the_static_dir() {
+        let static_dir = crate::test_support::temp_dir();
+        std::fs::write(static_dir.join("hello.txt"), "Hello from the static dir").unwrap();
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let base = serve_app(app(state(&llm, Arc::new(MemoryStorage::default())), &static_dir)).await;
+
+        for path in ["/static/hello.txt", "/hello.txt"] {
+            let response = reqwest::get(format!("{}{}", base, path)).await.unwrap();
+            assert_eq!(response.status(), 200, "{}", path);
+            assert_eq!(response.text().await.unwrap(), "Hello from the static dir");
+        }
+        let response = reqwest::get(format!("{}/static/missing.txt", base)).await.unwrap();
+        assert_eq!(response.status(), 404);
+        let _ = std::fs::remove_dir_all(static_dir);
+    }
+
+    #[tokio::test]
+    async fn line_splits_set_the_chapter_boundaries_exactly() {
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let base = serve(&llm, Arc::new(MemoryStorage::default())).await;
+        let text = "Opening\nThe first part.\n\nMiddle\nThe second part.\nClosing\nThe last part.\n";
+        let preview = |splits: &'static str| {
+            Form::default()
+                .text("line_splits", splits)
+                .file("text_file", "notes.txt", text.as_bytes())
+                .post(format!("{}/preview", base))
+        };
+
+        let response = preview("1,4,6").await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let chapters: Vec<(&str, u64, u64)> = body["chapters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|chapter| {
+                let position = |key: &str| chapter[key].as_u64().unwrap();
+                (chapter["title"].as_str().unwrap(), position("start_pos"), position("end_pos"))
+            })
+            .collect();
+        // Each chapter runs from after its line to the next split line
+        assert_eq!(chapters, [("Opening", 8, 25), ("Middle", 32, 49), ("Closing", 57, 72)]);
+        assert_eq!(&text[32..49], "The second part.\n");
+
+        for splits in ["4,1", "1,40"] {
+            assert_eq!(preview(splits).await.status(), 400, "{}", splits);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_api_paths_get_a_json_404() {
+        let static_dir = crate::test_support::temp_dir();
+        std::fs::write(static_dir.join("index.html"), "<h1>Static UI</h1>").unwrap();
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let base = serve_app(app(state(&llm, Arc::new(MemoryStorage::default())), &static_dir)).await;
+
+        for path in ["/api/unknown", "/api/index.html", "/api/v1/books?page=2"] {
+            let response = reqwest::get(format!("{}{}", base, path)).await.unwrap();
+            assert_eq!(response.status(), 404, "{}", path);
+            assert_eq!(response.headers()["content-type"], "application/json", "{}", path);
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(body["success"], false);
+            let route = path.split('?').next().unwrap();
+            assert_eq!(body["error"], format!("No API endpoint at {}", route));
+        }
+        // Other paths still fall back to static files
+        let response = reqwest::get(format!("{}/index.html", base)).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "<h1>Static UI</h1>");
+        let _ = std::fs::remove_dir_all(static_dir);
+    }
+
+    // Records the level and message of every event that reaches it
+    #[derive(Clone, Default)]
+    struct RecordedEvents(Arc<std::sync::Mutex<Vec<(tracing::Level, String)>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for RecordedEvents {
+        fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+            struct Message(String);
+            impl tracing::field::Visit for Message {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    if field.name() == "message" {
+                        self.0 = format!("{:?}", value);
+                    }
+                }
+            }
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            self.0.lock().unwrap().push((*event.metadata().level(), message.0));
+        }
+    }
+
+    #[tokio::test]
+    async fn the_error_log_level_silences_request_and_processing_logs() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        // Failed LLM calls are logged as warnings
+        let llm = MockServer::start(|_| (400, Vec::new())).await;
+        let text = format!("Chapter 1\n\n{0}\n\nChapter 2\n\n{0}\n", "Some text. ".repeat(20));
+        for filter in ["info", "error"] {
+            let events = RecordedEvents::default();
+            let subscriber = tracing_subscriber::registry().with(EnvFilter::new(filter)).with(events.clone());
+            // The test runtime runs the server on this thread too
+            let _default = tracing::subscriber::set_default(subscriber);
+
+            let base = serve(&llm, Arc::new(MemoryStorage::default())).await;
+            let response = Form::default()
+                .file("text_file", "book.txt", text.as_bytes())
+                .post(format!("{}/upload", base))
+                .await;
+            assert_eq!(response.status(), 200);
+
+            let events = events.0.lock().unwrap().clone();
+            if filter == "error" {
+                assert!(events.is_empty(), "{:?}", events);
+            } else {
+                assert!(events.iter().any(|(level, message)| *level == tracing::Level::INFO
+                    && message.starts_with("Request: POST /upload")));
+                assert!(events.iter().any(|(level, _)| *level == tracing::Level::WARN), "{:?}", events);
+            }
+        }
+    }
+
+    #[test]
+    fn a_processing_timeout_is_a_gateway_timeout() {
+        let timed_out = services::chapterizer::TimedOut(std::time::Duration::from_secs(30));
+        let error = processing_error(timed_out.into());
+        assert_eq!(error.status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(error.message, "Processing took longer than 30 seconds");
+    }
+
+    // A large file made up as it's read: byte `i` is `i % 251`
+    struct GeneratedFile {
+        len: u64,
+        // Bytes handed out so far
+        read: Arc<AtomicU64>,
+    }
+
+    impl tokio::io::AsyncRead for GeneratedFile {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let pos = self.read.load(Ordering::SeqCst);
+            let n = (self.len - pos).min(buf.remaining() as u64);
+            let bytes: Vec<u8> = (pos..pos + n).map(|i| (i % 251) as u8).collect();
+            buf.put_slice(&bytes);
+            self.read.fetch_add(n, Ordering::SeqCst);
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    // Storage holding one `GeneratedFile` under every name, which can only
+    // be read as a stream
+    struct GeneratedStorage {
+        len: u64,
+        read: Arc<AtomicU64>,
+    }
+
+    #[async_trait::async_trait]
+    impl Storage for GeneratedStorage {
+        async fn put(&self, _: &str, _: &[u8]) -> anyhow::Result<()> {
+            anyhow::bail!("read-only")
+        }
+
+        async fn get(&self, _: &str) -> anyhow::Result<Option<Vec<u8>>> {
+            anyhow::bail!("downloads must be streamed")
+        }
+
+        async fn get_stream(&self, _: &str) -> anyhow::Result<Option<services::storage::StoredFile>> {
+            let file = GeneratedFile { len: self.len, read: self.read.clone() };
+            Ok(Some(services::storage::StoredFile { len: self.len, reader: Box::pin(file) }))
+        }
+
+        async fn exists(&self, _: &str) -> anyhow::Result<bool> {
+            Ok(true)
+        }
+
+        async fn delete(&self, _: &str) -> anyhow::Result<()> {
+            anyhow::bail!("read-only")
+        }
+
+        async fn list(&self) -> anyhow::Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+
+        fn location(&self) -> String {
+            "generated".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn large_downloads_are_streamed_without_reading_the_whole_file_first() {
+        const LEN: u64 = 32 * 1024 * 1024;
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let read = Arc::new(AtomicU64::new(0));
+        let base = serve(&llm, Arc::new(GeneratedStorage { len: LEN, read: read.clone() })).await;
+
+        let mut response = reqwest::get(format!("{}/download/{}", base, uuid::Uuid::new_v4())).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.content_length(), Some(LEN));
+        let mut received = 0u64;
+        while let Some(chunk) = response.chunk().await.unwrap() {
+            if received == 0 {
+                // The download started long before the file was read through
+                assert!(read.load(Ordering::SeqCst) < LEN / 4);
+            }
+            for (offset, byte) in chunk.iter().enumerate() {
+                assert_eq!(*byte, ((received + offset as u64) % 251) as u8, "byte {}", received + offset as u64);
+            }
+            received += chunk.len() as u64;
+        }
+        assert_eq!(received, LEN);
+    }
+
+    #[tokio::test]
+    async fn files_that_are_mostly_unprintable_are_rejected() {
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let base = serve(&llm, Arc::new(MemoryStorage::default())).await;
+        // Bytes of a binary file, as a pseudo-random sequence
+        let mut state = 1u32;
+        let garbage: Vec<u8> = (0..4096)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let text = format!("Chapter 1\n\n{0}\n\nChapter 2\n\n{0}\n", "Some text. ".repeat(20));
+
+        let preview = |data: Vec<u8>| {
+            let url = format!("{}/preview", base);
+            async move {
+                Form::default()
+                    .file("text_file", "book.txt", &data)
+                    .text("lossy_utf8", "true")
+                    .text("min_printable_ratio", "0.9")
+                    .post(url)
+                    .await
+            }
+        };
+        let response = preview(garbage).await;
+        assert_eq!(response.status(), 400);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(body["error"].as_str().unwrap().starts_with("Uploaded file doesn't look like text"), "{}", body);
+
+        let response = preview(text.into_bytes()).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["chapter_count"], 2);
+    }
+
+    #[tokio::test]
+    async fn the_inline_epub_is_the_same_as_the_downloaded_one() {
+        use base64::Engine;
+
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let base = serve(&llm, Arc::new(MemoryStorage::default())).await;
+        let text = format!("Chapter 1\n\n{0}\n\nChapter 2\n\n{0}\n", "Some text. ".repeat(20));
+
+        let response = Form::default()
+            .text("strictness", "low")
+            .text("inline", "true")
+            .file("text_file", "book.txt", text.as_bytes())
+            .post(&format!("{}/upload", base))
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let inline = base64::engine::general_purpose::STANDARD
+            .decode(body["epub_base64"].as_str().unwrap())
+            .unwrap();
+        let url = format!("{}{}", base, body["download_url"].as_str().unwrap());
+        let downloaded = reqwest::get(url).await.unwrap().bytes().await.unwrap();
+        assert_eq!(inline, downloaded);
+        assert!(crate::test_support::epub_files(&inline).contains_key("mimetype"));
+    }
+
+    #[tokio::test]
+    async fn the_diff_reports_added_and_removed_chapters() {
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let base = serve(&llm, Arc::new(MemoryStorage::default())).await;
+        let text = format!(
+            "Chapter 1\n\n{0}\n\n1. An Interlude\n\n{0}\n\nChapter 2\n\nToo short.\n\nChapter 3\n\n{0}\n",
+            "Some text for this chapter. ".repeat(20)
+        );
+
+        let response = Form::default()
+            .text("a.strictness", "high")
+            .text("b.strictness", "low")
+            .text("b.stop_patterns", "^Chapter 3$")
+            .file("text_file", "book.txt", text.as_bytes())
+            .post(format!("{}/diff", base))
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!((body["a"]["chapter_count"].as_u64(), body["b"]["chapter_count"].as_u64()), (Some(2), Some(3)));
+        let diff = &body["diff"];
+        let titles = |boundaries: &serde_json::Value| -> Vec<String> {
+            boundaries.as_array().unwrap().iter().map(|boundary| boundary["title"].as_str().unwrap().to_string()).collect()
+        };
+        // Only the low strictness run splits at the generic heading and the
+        // short chapter, and it stops before Chapter 3
+        let added = titles(&diff["added"]);
+        assert_eq!(added.len(), 2);
+        assert!(added[0].ends_with("An Interlude") && added[1] == "Chapter 2", "{:?}", added);
+        assert_eq!(diff["added"][1]["start_pos"].as_u64().unwrap() as usize, text.find("Too short.").unwrap());
+        assert_eq!(titles(&diff["removed"]), ["Chapter 3"]);
+        assert_eq!(diff["retitled"], serde_json::json!([]));
+        assert_eq!(diff["unchanged"], 1);
+    }
+
+    #[tokio::test]
+    async fn job_progress_only_goes_up_while_chapters_are_validated() {
+        let valid = r#"{"is_valid": true, "suggested_title": null, "has_content_modified": false, "suggestions": null}"#;
+        let delay = std::time::Duration::from_millis(50);
+        let llm = MockServer::start_delayed(move |_| (200, valid.as_bytes().to_vec(), delay)).await;
+        let base = serve(&llm, Arc::new(MemoryStorage::default())).await;
+        let book: String = (1..=5)
+            .map(|n| format!("Chapter {}\n\n{}\n\n", n, "Some text for this chapter. ".repeat(20)))
+            .collect();
+
+        let response = Form::default()
+            .file("text_file", "book.txt", book.as_bytes())
+            .post(&format!("{}/jobs", base))
+            .await;
+        assert_eq!(response.status(), 202);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let status_url = format!("{}{}", base, body["status_url"].as_str().unwrap());
+
+        let client = reqwest::Client::new();
+        let mut seen = Vec::new();
+        let job = loop {
+            let job: serde_json::Value = client.get(&status_url).send().await.unwrap().json().await.unwrap();
+            seen.push(job["progress"].as_u64().unwrap());
+            if job["status"] != "running" {
+                break job;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        assert_eq!(job["status"], "completed", "{}", job);
+        assert!(seen.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", seen);
+        assert_eq!(seen.last(), Some(&100));
+        // Polls landed between LLM calls, not only before and after them
+        let partial: std::collections::BTreeSet<_> = seen.iter().filter(|p| (1..100).contains(*p)).collect();
+        assert!(partial.len() >= 3, "{:?}", seen);
+        assert!(!llm.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_book_is_titled_after_the_uploaded_file_when_enabled() {
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let base = serve(&llm, Arc::new(MemoryStorage::default())).await;
+        let text = format!("Chapter 1\n\n{0}\n\nChapter 2\n\n{0}\n", "Some text. ".repeat(20));
+
+        for (title_from_filename, title) in [("true", "my_novel"), ("false", "Generated Book")] {
+            let response = Form::default()
+                .text("strictness", "low")
+                .text("title_from_filename", title_from_filename)
+                .file("text_file", "my_novel.txt", text.as_bytes())
+                .post(&format!("{}/upload", base))
+                .await;
+            assert_eq!(response.status(), 200);
+            let body: serde_json::Value = response.json().await.unwrap();
+            let url = format!("{}{}", base, body["download_url"].as_str().unwrap());
+            let epub = reqwest::get(url).await.unwrap().bytes().await.unwrap();
+            let files = crate::test_support::epub_files(&epub);
+            let opf = files.iter().find(|(path, _)| path.ends_with("content.opf")).unwrap().1;
+            assert!(opf.contains(&format!(">{}</dc:title>", title)), "{}", opf);
+            assert_eq!(opf.contains("my_novel.txt"), title_from_filename == "true", "{}", opf);
+        }
+    }
+
+    #[tokio::test]
+    async fn text_without_chapters_is_rejected_when_a_single_chapter_isnt_allowed() {
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let storage = Arc::new(MemoryStorage::default());
+        let base = serve(&llm, storage.clone()).await;
+        let text = "Just some prose without any headings. ".repeat(50);
+
+        let response = Form::default()
+            .text("strictness", "low")
+            .text("allow_single_chapter", "false")
+            .file("text_file", "essay.txt", text.as_bytes())
+            .post(&format!("{}/upload", base))
+            .await;
+        assert_eq!(response.status(), 422);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["success"], false);
+        assert!(body["error"].as_str().unwrap().contains("No chapters were detected"), "{}", body);
+        assert!(storage.list().await.unwrap().is_empty());
+
+        // Previews still show the fallback, and uploads accept it by default
+        let response = Form::default()
+            .text("allow_single_chapter", "false")
+            .file("text_file", "essay.txt", text.as_bytes())
+            .post(&format!("{}/preview", base))
+            .await;
+        assert_eq!(response.status(), 200);
+        let response = Form::default()
+            .text("strictness", "low")
+            .file("text_file", "essay.txt", text.as_bytes())
+            .post(&format!("{}/upload", base))
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["chapter_count"], 1);
+    }
+
+    #[tokio::test]
+    async fn chapters_are_listed_a_page_at_a_time() {
+        let llm = MockServer::start(|_| (500, Vec::new())).await;
+        let base = serve(&llm, Arc::new(MemoryStorage::default())).await;
+        let book: String = (1..=5)
+            .map(|n| format!("Chapter {}\n\nText of chapter {}. {}\n\n", n, n, "Some text. ".repeat(20)))
+            .collect();
+        let response = Form::default()
+            .text("strictness", "low")
+            .file("text_file", "book.txt", book.as_bytes())
+            .post(&format!("{}/upload", base))
+            .await;
+        let body: serde_json::Value = response.json().await.unwrap();
+        let id = body["download_url"].as_str().unwrap().trim_start_matches("/download/").to_string();
+
+        let url = format!("{}/chapters/{}?offset=1&limit=2&include_content=false", base, id);
+        let page: serde_json::Value = reqwest::get(url)
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(page["total"], 5);
+        assert_eq!(page["offset"], 1);
+        let chapters = page["chapters"].as_array().unwrap();
+        let titles: Vec<&str> = chapters.iter().map(|chapter| chapter["title"].as_str().unwrap()).collect();
+        assert_eq!(titles, ["Chapter 2", "Chapter 3"]);
+        for chapter in chapters {
+            assert!(chapter.get("content").is_none(), "{}", chapter);
+            assert!(chapter["byte_length"].as_u64().unwrap() > 0);
+        }
+
+        // Content is included by default, and a page past the end is empty
+        let page: serde_json::Value = reqwest::get(format!("{}/chapters/{}?offset=4&limit=1", base, id))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(page["chapters"][0]["content"].as_str().unwrap().starts_with("Text of chapter 5."), "{}", page);
+        let page: serde_json::Value =
+            reqwest::get(format!("{}/chapters/{}?offset=9", base, id)).await.unwrap().json().await.unwrap();
+        assert_eq!(page["chapters"].as_array().unwrap().len(), 0);
+        assert_eq!(page["total"], 5);
+
+        let response = reqwest::get(format!("{}/chapters/{}", base, uuid::Uuid::new_v4())).await.unwrap();
+        assert_eq!(response.status(), 404);
+    }
 }
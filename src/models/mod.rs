@@ -1,17 +1,412 @@
 use serde::{Deserialize, Serialize};
 
+/// The kind of section a detected chapter represents in the book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SectionRole {
+    #[default]
+    Chapter,
+    Preface,
+    Prologue,
+    Epilogue,
+    Afterword,
+    Appendix,
+    Interlude,
+}
+
+impl SectionRole {
+    /// Parse a section type as named by the LLM classification prompt.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "chapter" => Some(SectionRole::Chapter),
+            "preface" | "foreword" | "introduction" => Some(SectionRole::Preface),
+            "prologue" => Some(SectionRole::Prologue),
+            "epilogue" => Some(SectionRole::Epilogue),
+            "afterword" => Some(SectionRole::Afterword),
+            "appendix" => Some(SectionRole::Appendix),
+            "interlude" => Some(SectionRole::Interlude),
+            _ => None,
+        }
+    }
+
+    /// Term from the EPUB structural semantics vocabulary, for `epub:type`.
+    /// Interludes have no term of their own.
+    pub fn epub_type(self) -> Option<&'static str> {
+        match self {
+            SectionRole::Chapter => Some("chapter"),
+            SectionRole::Preface => Some("preface"),
+            SectionRole::Prologue => Some("prologue"),
+            SectionRole::Epilogue => Some("epilogue"),
+            SectionRole::Afterword => Some("afterword"),
+            SectionRole::Appendix => Some("appendix"),
+            SectionRole::Interlude => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chapter {
+    /// Display title. Titles are opaque: they are escaped wherever they are
+    /// rendered and never run through chapter detection again, even if they
+    /// mention another chapter (e.g. "About Chapter 3").
     pub title: String,
     pub content: String,
     pub start_pos: usize,
     pub end_pos: usize,
+    #[serde(default)]
+    pub role: SectionRole,
+    /// Part of the main reading flow. Sidebars and notes can be marked
+    /// `false` in the sidecar to get `linear="no"` in the EPUB spine
+    #[serde(default = "default_linear")]
+    pub linear: bool,
+}
+
+fn default_linear() -> bool {
+    true
+}
+
+impl Chapter {
+    /// Size of the chapter content in UTF-8 bytes.
+    pub fn byte_length(&self) -> usize {
+        self.content.len()
+    }
+
+    /// Length of the chapter content in characters (Unicode scalar values).
+    pub fn char_length(&self) -> usize {
+        self.content.chars().count()
+    }
+}
+
+/// Serialize chapters with their `byte_length` and `char_length` added.
+/// The lengths are ignored when reading them back.
+fn serialize_with_lengths<S: serde::Serializer>(chapters: &[Chapter], serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct WithLengths<'a> {
+        #[serde(flatten)]
+        chapter: &'a Chapter,
+        byte_length: usize,
+        char_length: usize,
+    }
+    serializer.collect_seq(chapters.iter().map(|chapter| WithLengths {
+        chapter,
+        byte_length: chapter.byte_length(),
+        char_length: chapter.char_length(),
+    }))
+}
+
+/// How eager chapter detection is to split the text. Each level maps to a
+/// fixed set of detection parameters (see `Strictness::params`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strictness {
+    /// Every heading pattern, no minimum length, no LLM validation
+    Low,
+    /// Every heading pattern, no minimum length, LLM validation
+    #[default]
+    Medium,
+    /// Only explicit chapter headings, short chapters merged, LLM validation
+    High,
+}
+
+/// What kind of text chapter detection looks for headings in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectionMode {
+    /// Books: chapter headings and sections such as prefaces
+    #[default]
+    Prose,
+    /// Screenplays and stage plays: one chapter per scene
+    Script,
+    /// CommonMark documents: one chapter per top-level heading, found by
+    /// parsing the document rather than matching lines, and rendered as
+    /// Markdown
+    Markdown,
+}
+
+/// Title given to bilingual headings such as "Chapter 2 第二章 The Storm".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BilingualTitles {
+    /// Both labels followed by the title: "Chapter 2 第二章 The Storm"
+    #[default]
+    Both,
+    /// The title, or the Latin label if there is none: "Chapter 2"
+    Latin,
+    /// The title, or the CJK label if there is none: "第二章"
+    Cjk,
+    /// Don't treat bilingual headings specially
+    Off,
+}
+
+/// The options that decide which lines chapter detection picks up as
+/// headings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetectionSettings {
+    pub strictness: Strictness,
+    pub mode: DetectionMode,
+    pub form_feeds: bool,
+    /// Extra heading keywords, such as "Scene" or "卷"
+    pub keywords: Vec<String>,
+    /// Detect in segments on a thread pool
+    pub parallel: bool,
+    pub bilingual_titles: BilingualTitles,
+}
+
+/// Detection parameters selected by a `Strictness` level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetectionParams {
+    /// Also match generic headings such as `# Title`, `1. Title` or `Part 1`
+    pub loose_patterns: bool,
+    /// Chapters with fewer characters are merged into the previous one
+    pub min_chapter_chars: usize,
+    /// Validate titles and boundaries with the LLM
+    pub use_llm: bool,
+}
+
+impl Strictness {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "low" => Some(Strictness::Low),
+            "medium" | "" => Some(Strictness::Medium),
+            "high" => Some(Strictness::High),
+            _ => None,
+        }
+    }
+
+    pub fn params(self) -> DetectionParams {
+        match self {
+            Strictness::Low => DetectionParams {
+                loose_patterns: true,
+                min_chapter_chars: 0,
+                use_llm: false,
+            },
+            Strictness::Medium => DetectionParams {
+                loose_patterns: true,
+                min_chapter_chars: 0,
+                use_llm: true,
+            },
+            Strictness::High => DetectionParams {
+                loose_patterns: false,
+                min_chapter_chars: 200,
+                use_llm: true,
+            },
+        }
+    }
+}
+
+/// A file format that can be generated from the detected chapters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Epub,
+    Markdown,
+    /// A single self-contained HTML page with a table of contents
+    Html,
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 3] =
+        [OutputFormat::Epub, OutputFormat::Markdown, OutputFormat::Html];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "epub" => Some(OutputFormat::Epub),
+            "markdown" | "md" => Some(OutputFormat::Markdown),
+            "html" => Some(OutputFormat::Html),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Epub => "epub",
+            OutputFormat::Markdown => "markdown",
+            OutputFormat::Html => "html",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Epub => "epub",
+            OutputFormat::Markdown => "md",
+            OutputFormat::Html => "html",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Epub => "application/epub+zip",
+            OutputFormat::Markdown => "text/markdown; charset=utf-8",
+            OutputFormat::Html => "text/html; charset=utf-8",
+        }
+    }
+}
+
+/// Parse stop patterns given one regular expression per line. An empty
+/// value disables stopping.
+fn parse_stop_patterns(value: &str) -> Result<Vec<regex::Regex>, String> {
+    value
+        .lines()
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| {
+            regex::Regex::new(pattern).map_err(|e| format!("Invalid stop pattern '{}': {}", pattern, e))
+        })
+        .collect()
+}
+
+/// A regex replacement applied to every detected chapter title.
+#[derive(Debug, Clone)]
+pub struct TitleRewrite {
+    pub pattern: regex::Regex,
+    /// Replacement text; may refer to capture groups as `$1` or `${name}`
+    pub replacement: String,
+}
+
+/// Parse a `pattern=>replacement` title rewrite rule. An empty value means
+/// no rewriting.
+fn parse_title_rewrite(value: &str) -> Result<Option<TitleRewrite>, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let (pattern, replacement) = value
+        .split_once("=>")
+        .ok_or_else(|| format!("Invalid title rewrite '{}': expected pattern=>replacement", value))?;
+    let pattern = pattern.trim();
+    if pattern.is_empty() {
+        return Err(format!("Invalid title rewrite '{}': the pattern is empty", value));
+    }
+    let pattern = regex::Regex::new(pattern)
+        .map_err(|e| format!("Invalid title rewrite pattern '{}': {}", pattern, e))?;
+    Ok(Some(TitleRewrite {
+        pattern,
+        replacement: replacement.trim().to_string(),
+    }))
+}
+
+// Longest heading keyword accepted in `keywords`
+const KEYWORD_MAX_CHARS: usize = 20;
+
+// Most heading keywords accepted in `keywords`; each adds a pattern that
+// every line is matched against
+const MAX_KEYWORDS: usize = 20;
+
+/// Parse heading keywords separated by commas or newlines.
+fn parse_keywords(value: &str) -> Result<Vec<String>, String> {
+    let keywords: Vec<&str> = value
+        .split([',', '，', '\n'])
+        .map(str::trim)
+        .filter(|keyword| !keyword.is_empty())
+        .collect();
+    if keywords.len() > MAX_KEYWORDS {
+        return Err(format!("At most {} keywords are accepted", MAX_KEYWORDS));
+    }
+    keywords
+        .into_iter()
+        .map(|keyword| {
+            if keyword.chars().count() > KEYWORD_MAX_CHARS {
+                return Err(format!(
+                    "Keyword '{}' is longer than {} characters",
+                    keyword, KEYWORD_MAX_CHARS
+                ));
+            }
+            Ok(keyword.to_string())
+        })
+        .collect()
+}
+
+fn default_formats() -> Vec<OutputFormat> {
+    vec![OutputFormat::Epub]
 }
 
 #[derive(Debug, Serialize)]
 pub struct ProcessResult {
     pub chapters: Vec<Chapter>,
+    /// ID shared by every output file generated for this upload
     pub epub_id: String,
+    /// Formats that were generated under `epub_id`
+    pub formats: Vec<OutputFormat>,
+    /// Non-fatal problems encountered while processing
+    pub warnings: Vec<String>,
+}
+
+/// Chapters found by detection alone, for `/preview`.
+#[derive(Debug, Serialize)]
+pub struct PreviewResult {
+    pub chapters: Vec<Chapter>,
+    /// Lines matched by each heading pattern, keyed by the pattern's regex
+    pub pattern_stats: std::collections::BTreeMap<String, usize>,
+    pub warnings: Vec<String>,
+}
+
+/// Where a chapter starts and ends and what it's called, for `/diff`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChapterBoundary {
+    pub title: String,
+    pub start_pos: usize,
+    pub end_pos: usize,
+}
+
+/// A chapter found at the same position by both runs, under different titles.
+#[derive(Debug, Clone, Serialize)]
+pub struct RetitledChapter {
+    pub start_pos: usize,
+    pub title_a: String,
+    pub title_b: String,
+}
+
+/// Differences between two detection runs over the same text. Chapters are
+/// matched by where their content starts.
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentationDiff {
+    /// Chapters only run `b` found
+    pub added: Vec<ChapterBoundary>,
+    /// Chapters only run `a` found
+    pub removed: Vec<ChapterBoundary>,
+    pub retitled: Vec<RetitledChapter>,
+    /// Chapters both runs found with the same title
+    pub unchanged: usize,
+}
+
+/// Stored next to each generated EPUB so it can be rebuilt without
+/// re-uploading the text.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChapterSidecar {
+    /// Chapters as found by detection, before LLM validation
+    #[serde(serialize_with = "serialize_with_lengths")]
+    pub detected_chapters: Vec<Chapter>,
+    /// Chapters as written to the EPUB
+    #[serde(serialize_with = "serialize_with_lengths")]
+    pub chapters: Vec<Chapter>,
+    #[serde(default)]
+    pub epub: EpubOptions,
+    #[serde(default = "default_formats")]
+    pub formats: Vec<OutputFormat>,
+}
+
+/// Description of a generated EPUB for downstream cataloging, written as
+/// `{id}.manifest.json` when requested.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BookManifest {
+    pub id: String,
+    pub title: String,
+    pub author: String,
+    pub language: String,
+    /// RFC 3339 timestamp of when the EPUB was generated
+    pub generated_at: String,
+    /// Name and version of the tool that generated the EPUB
+    pub generator: String,
+    pub chapters: Vec<ManifestChapter>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestChapter {
+    pub title: String,
+    pub role: SectionRole,
+    /// File of the chapter inside the EPUB
+    pub file: String,
+    /// Length of the chapter content in characters
+    pub length: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,4 +415,823 @@ pub struct LLMResponse {
     pub suggested_title: Option<String>,
     pub has_content_modified: bool,
     pub suggestions: Option<String>,
+    /// Section type (chapter, preface, appendix or interlude), when the
+    /// prompt asked for a classification
+    #[serde(default)]
+    pub chapter_type: Option<String>,
+}
+
+/// The LLM's answer when asked for the book's genres and subjects.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubjectsResponse {
+    #[serde(default)]
+    pub subjects: Vec<String>,
+}
+
+/// EPUB specification version to target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EpubVersion {
+    /// EPUB 2.0.1 with an NCX table of contents, for legacy readers
+    V2,
+    /// EPUB 3.0.1 with a navigation document
+    #[default]
+    V3,
+}
+
+/// How chapter content is interpreted when rendering it into the EPUB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentFormat {
+    /// Plain text, escaped and split into paragraphs on blank lines
+    #[default]
+    Text,
+    /// Markdown, rendered to HTML and sanitized. Fenced code blocks become
+    /// `<pre>` blocks
+    Markdown,
+    /// Preformatted text kept verbatim in a `<pre>` block, for technical
+    /// documents where whitespace matters
+    Preformatted,
+}
+
+/// What happens to Markdown images whose target isn't an absolute http(s)
+/// URL, such as relative links to assets that weren't uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkdownImages {
+    /// Replace the image with its alt text
+    #[default]
+    AltText,
+    /// Remove the image
+    Drop,
+    /// Also download remote images from allow-listed hosts into the EPUB;
+    /// images that can't be embedded fall back to their alt text
+    Embed,
+}
+
+/// How chapter numbers are shown in front of EPUB table of contents entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TocNumbering {
+    /// Titles only
+    #[default]
+    None,
+    /// "1. Title"
+    Arabic,
+    /// "第一章 Title"
+    Chinese,
+}
+
+/// How chapter files inside the EPUB are named.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChapterFileNaming {
+    /// `chap_1.xhtml`, `chap_2.xhtml`, ...
+    #[default]
+    Index,
+    /// Slug of the chapter title, e.g. `the-beginning.xhtml`
+    Title,
+}
+
+/// What to do with text that comes before the first detected heading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrontMatter {
+    /// Leave it out of the book
+    #[default]
+    Discard,
+    /// Add it to the start of the first chapter
+    PrependToFirst,
+    /// Give it its own "Front Matter" section
+    SeparateFrontMatter,
+}
+
+impl FrontMatter {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "discard" | "" => Some(FrontMatter::Discard),
+            "prepend_to_first" => Some(FrontMatter::PrependToFirst),
+            "separate_front_matter" | "separate" => Some(FrontMatter::SeparateFrontMatter),
+            _ => None,
+        }
+    }
+}
+
+/// How the uploaded file is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputFormat {
+    /// HTML if the file looks like an HTML document, plain text otherwise
+    #[default]
+    Auto,
+    Text,
+    /// Markup is stripped and `<h1>`/`<h2>` headings start chapters
+    Html,
+}
+
+/// What to do with text after a line matching a stop pattern (an "end of
+/// book" marker such as "全文完").
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AfterEnd {
+    /// Leave it out of the book
+    #[default]
+    Discard,
+    /// Give it its own "Trailing Content" section outside the reading order
+    SeparateSection,
+}
+
+/// How paragraphs are separated in the EPUB stylesheet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParagraphStyle {
+    /// `Indent` for mostly CJK text, `Spaced` otherwise
+    #[default]
+    Auto,
+    /// First-line indent with no space between paragraphs (CJK convention)
+    Indent,
+    /// Space between paragraphs with no indent (Western web convention)
+    Spaced,
+}
+
+/// Options controlling how the EPUB file is generated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EpubOptions {
+    pub version: EpubVersion,
+    pub content_format: ContentFormat,
+    pub markdown_images: MarkdownImages,
+    /// Numbering added to TOC entries of chapters (not their headings)
+    pub toc_numbering: TocNumbering,
+    /// Append an occurrence index to TOC entries (not headings) of titles
+    /// used by more than one chapter, e.g. "Introduction (2)"
+    pub disambiguate_titles: bool,
+    pub file_naming: ChapterFileNaming,
+    /// Also write a JSON manifest describing the EPUB
+    pub manifest: bool,
+    /// Start the Markdown output with a UTF-8 byte order mark, for Windows
+    /// tools that expect one
+    pub bom: bool,
+    pub paragraph_style: ParagraphStyle,
+    /// Link footnote markers in plain text to their footnote text
+    pub footnotes: bool,
+    /// Replace curly quotes, dashes and non-breaking spaces with plain
+    /// ASCII in the output (CJK punctuation is kept)
+    pub normalize_punctuation: bool,
+    /// Collapse runs of blank lines in plain text content into single
+    /// paragraph breaks, keeping scene break lines as their own paragraphs
+    pub normalize_spacing: bool,
+    /// Start the EPUB with a generated title page (title, author, date)
+    pub title_page: bool,
+    /// Reading order as 1-based chapter numbers, when it differs from the
+    /// TOC (chapter) order
+    pub spine_order: Option<Vec<usize>>,
+    /// Publication and modification date (RFC 3339, UTC) to record in the
+    /// EPUB instead of the time it's generated, for reproducible builds
+    pub date: Option<String>,
+    /// Include the legacy `toc.ncx` in EPUB 3 files alongside the nav
+    /// document, for older readers (EPUB 2 files always have it)
+    pub ncx: bool,
+    /// List the title page, TOC and first body chapter as landmarks in the
+    /// navigation document (EPUB 3) and mark the body start in the guide
+    pub landmarks: bool,
+    /// Add a table of contents page to the reading order, besides the
+    /// navigation readers build from the nav document or NCX
+    pub inline_toc: bool,
+    /// Split chapters larger than this many bytes into parts at paragraph
+    /// breaks; only the first part is listed in the TOC
+    pub max_chapter_bytes: Option<usize>,
+    /// Genres and subjects recorded as `dc:subject` metadata
+    pub subjects: Vec<String>,
+    /// Title the book after the uploaded file's name (without extension)
+    /// and record the name in the EPUB metadata
+    pub title_from_filename: bool,
+    /// Name of the uploaded file, as sent by the client
+    pub source_filename: Option<String>,
+    /// Times to retry writing the EPUB after a failed write. Server
+    /// configuration only
+    pub write_retries: u32,
+}
+
+// Default number of retries after a failed EPUB write
+const DEFAULT_WRITE_RETRIES: u32 = 2;
+
+impl Default for EpubOptions {
+    fn default() -> Self {
+        EpubOptions {
+            version: EpubVersion::default(),
+            content_format: ContentFormat::default(),
+            markdown_images: MarkdownImages::default(),
+            toc_numbering: TocNumbering::default(),
+            disambiguate_titles: false,
+            file_naming: ChapterFileNaming::default(),
+            manifest: false,
+            bom: false,
+            paragraph_style: ParagraphStyle::default(),
+            footnotes: false,
+            normalize_punctuation: false,
+            normalize_spacing: false,
+            title_page: false,
+            spine_order: None,
+            date: None,
+            ncx: true,
+            landmarks: true,
+            inline_toc: true,
+            max_chapter_bytes: None,
+            subjects: Vec::new(),
+            title_from_filename: false,
+            source_filename: None,
+            write_retries: DEFAULT_WRITE_RETRIES,
+        }
+    }
+}
+
+// Default cap on EPUBs returned inline in the upload response (10 MiB)
+const DEFAULT_INLINE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Per-request options supplied as extra multipart fields on `/upload`.
+#[derive(Debug, Clone)]
+pub struct ProcessOptions {
+    /// LLM endpoint to use instead of the server default
+    pub llm_url: Option<String>,
+    /// LLM model to use instead of the server default
+    pub llm_model: Option<String>,
+    /// LLM sampling seed to use instead of the server default
+    pub llm_seed: Option<u64>,
+    /// Report chapter positions as byte offsets into the uploaded file
+    /// rather than into the normalized text
+    pub original_positions: bool,
+    /// Estimated LLM tokens one upload may use; chapters beyond the budget
+    /// skip LLM validation
+    pub llm_token_budget: Option<usize>,
+    /// Only LLM-validate every Nth chapter (plus the first, last, longest
+    /// and shortest)
+    pub llm_sample_every: Option<usize>,
+    /// LLM boundary checks never merge the book below this many chapters
+    pub llm_min_chapters: Option<usize>,
+    /// Also ask the LLM to classify each chapter's section type, which sets
+    /// its role (TOC numbering and `epub:type`)
+    pub llm_classify: bool,
+    /// Send the LLM only the whole sentences within this many characters
+    /// of a chapter boundary, instead of both full chapters
+    pub llm_boundary_chars: Option<usize>,
+    /// Send the LLM only structural features of each chapter (length, first
+    /// and last lines, whether it starts or ends mid-sentence) instead of
+    /// its text
+    pub llm_anonymize: bool,
+    /// Ask the LLM for the book's genres and subjects and record them in
+    /// the EPUB metadata
+    pub llm_subjects: bool,
+    /// Clean up detected titles (casing, redundant "Chapter N -" prefixes)
+    pub normalize_titles: bool,
+    /// Also remove control and zero-width characters from chapter content
+    /// (titles are always cleaned)
+    pub strip_invisible: bool,
+    /// Pause between consecutive LLM calls, to go easy on shared backends
+    pub llm_call_delay_ms: u64,
+    /// Random extra pause of up to this many milliseconds added to the delay
+    pub llm_call_jitter_ms: u64,
+    /// Longest a single LLM call may take; slower calls are skipped and the
+    /// chapter is kept as detected
+    pub llm_call_timeout: Option<std::time::Duration>,
+    /// Keep the decoded source text next to the outputs for debugging.
+    /// Server configuration only, off by default for privacy
+    pub keep_source: bool,
+    pub input_format: InputFormat,
+    pub strictness: Strictness,
+    /// Minimum chapter length in characters, instead of the one set by
+    /// `strictness`
+    pub min_chapter_chars: Option<usize>,
+    /// Target range for the number of chapters; detection settings are
+    /// adjusted automatically when the result falls outside it
+    pub min_chapters: Option<usize>,
+    pub max_chapters: Option<usize>,
+    pub mode: DetectionMode,
+    /// Start a new chapter at every form feed
+    pub form_feeds: bool,
+    /// How headings with both a Latin and a CJK label are titled
+    pub bilingual_titles: BilingualTitles,
+    /// Split large texts into segments and detect headings in them in
+    /// parallel
+    pub parallel_detection: bool,
+    /// Replace invalid UTF-8 in the upload instead of rejecting it
+    pub lossy_utf8: bool,
+    /// Reject uploads whose share of printable characters is below this
+    /// fraction (0 to 1)
+    pub min_printable_ratio: Option<f64>,
+    /// 1-based line numbers that start chapters, replacing detection and
+    /// LLM validation
+    pub line_splits: Option<Vec<usize>>,
+    pub front_matter: FrontMatter,
+    /// What to do with text before the heading when only one heading is
+    /// found, instead of `front_matter`. When unset, `front_matter` applies
+    /// but the text is prepended to the chapter rather than discarded
+    pub single_marker_front_matter: Option<FrontMatter>,
+    /// Lines marking the end of the book; detection stops at the first one
+    pub stop_patterns: Vec<regex::Regex>,
+    /// Rewrite applied to every detected chapter title
+    pub title_rewrite: Option<TitleRewrite>,
+    /// Words that start chapter headings in addition to the built-in ones
+    /// (e.g. "Scene" for "Scene 3", or "卷" for "第三卷")
+    pub keywords: Vec<String>,
+    pub after_end: AfterEnd,
+    /// Only keep headings of the style that dominates the text
+    pub dominant_pattern: bool,
+    /// Warn when two heading styles match a similar number of lines
+    pub ambiguity_warnings: bool,
+    /// Keep runs of closely spaced numbered-list headings (`1. ...`) inside
+    /// their chapter instead of splitting at each item
+    pub collapse_lists: bool,
+    /// Merge chapters whose heading is marked as a continuation ("(continued)",
+    /// "（续）") into the chapter before them
+    pub merge_continuations: bool,
+    /// Use a title-like first line as the title of chapters whose heading
+    /// is only a number
+    pub promote_title_lines: bool,
+    /// Remove a line (such as the book's title) repeated at the start or
+    /// end of most chapters
+    pub strip_banner: bool,
+    /// Sort chapters whose headings are numbered out of order
+    pub reorder_chapters: bool,
+    /// Chapter count the caller expects; a different count is reported as
+    /// a warning
+    pub expected_chapters: Option<usize>,
+    /// Fail instead of warning when `expected_chapters` doesn't match
+    pub strict: bool,
+    /// Accept text without detected chapters as a single "Complete Text"
+    /// chapter; otherwise processing fails
+    pub allow_single_chapter: bool,
+    /// Start chapters at their first line of content rather than at the
+    /// blank lines after their heading
+    pub skip_blank_lines: bool,
+    /// Output files to generate, all sharing one ID
+    pub formats: Vec<OutputFormat>,
+    pub epub: EpubOptions,
+    /// Overall deadline for processing one upload. Server configuration only
+    pub max_processing: Option<std::time::Duration>,
+    /// Also return the EPUB base64-encoded in the `/upload` response
+    pub inline: bool,
+    /// Largest EPUB returned inline, in bytes. Server configuration only
+    pub inline_max_bytes: u64,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        ProcessOptions {
+            llm_url: None,
+            llm_model: None,
+            llm_seed: None,
+            original_positions: false,
+            llm_token_budget: None,
+            llm_sample_every: None,
+            llm_min_chapters: None,
+            llm_classify: false,
+            llm_boundary_chars: None,
+            llm_anonymize: false,
+            llm_subjects: false,
+            normalize_titles: false,
+            strip_invisible: false,
+            llm_call_delay_ms: 0,
+            llm_call_jitter_ms: 0,
+            llm_call_timeout: None,
+            keep_source: false,
+            input_format: InputFormat::default(),
+            strictness: Strictness::default(),
+            min_chapter_chars: None,
+            min_chapters: None,
+            max_chapters: None,
+            mode: DetectionMode::default(),
+            form_feeds: true,
+            bilingual_titles: BilingualTitles::default(),
+            parallel_detection: false,
+            lossy_utf8: false,
+            min_printable_ratio: None,
+            line_splits: None,
+            front_matter: FrontMatter::default(),
+            single_marker_front_matter: None,
+            stop_patterns: Vec::new(),
+            title_rewrite: None,
+            keywords: Vec::new(),
+            after_end: AfterEnd::default(),
+            dominant_pattern: false,
+            ambiguity_warnings: true,
+            collapse_lists: true,
+            merge_continuations: false,
+            promote_title_lines: false,
+            strip_banner: false,
+            reorder_chapters: false,
+            skip_blank_lines: true,
+            expected_chapters: None,
+            strict: false,
+            allow_single_chapter: true,
+            formats: default_formats(),
+            epub: EpubOptions::default(),
+            max_processing: None,
+            inline: false,
+            inline_max_bytes: DEFAULT_INLINE_MAX_BYTES,
+        }
+    }
+}
+
+impl ProcessOptions {
+    pub fn detection(&self) -> DetectionSettings {
+        DetectionSettings {
+            strictness: self.strictness,
+            mode: self.mode,
+            form_feeds: self.form_feeds,
+            keywords: self.keywords.clone(),
+            parallel: self.parallel_detection,
+            bilingual_titles: self.bilingual_titles,
+        }
+    }
+
+    /// Options with server-wide defaults taken from the environment.
+    pub fn from_env() -> Self {
+        ProcessOptions {
+            llm_token_budget: env_parse("LLM_TOKEN_BUDGET"),
+            llm_sample_every: env_parse("LLM_SAMPLE_EVERY").filter(|every| *every > 0),
+            llm_min_chapters: env_parse("LLM_MIN_CHAPTERS"),
+            llm_classify: env_flag("LLM_CLASSIFY"),
+            llm_boundary_chars: env_parse("LLM_BOUNDARY_CHARS"),
+            llm_anonymize: env_flag("LLM_ANONYMIZE"),
+            llm_subjects: env_flag("LLM_SUBJECTS"),
+            llm_call_delay_ms: env_parse("LLM_CALL_DELAY_MS").unwrap_or(0),
+            llm_call_jitter_ms: env_parse("LLM_CALL_JITTER_MS").unwrap_or(0),
+            llm_call_timeout: env_parse("LLM_CALL_TIMEOUT_SECS")
+                .filter(|secs| *secs > 0)
+                .map(std::time::Duration::from_secs),
+            keep_source: env_flag("DEBUG_KEEP_SOURCE"),
+            dominant_pattern: env_flag("DOMINANT_PATTERN"),
+            parallel_detection: env_flag("PARALLEL_DETECTION"),
+            min_printable_ratio: env_parse("MIN_PRINTABLE_RATIO")
+                .filter(|ratio: &f64| (0.0..=1.0).contains(ratio)),
+            // Checked at startup by `check_env`
+            stop_patterns: std::env::var("STOP_PATTERNS")
+                .ok()
+                .and_then(|value| parse_stop_patterns(&value).ok())
+                .unwrap_or_default(),
+            epub: EpubOptions {
+                date: env_parse("SOURCE_DATE_EPOCH")
+                    .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+                    .map(format_date),
+                title_from_filename: env_flag("TITLE_FROM_FILENAME"),
+                write_retries: env_parse("EPUB_WRITE_RETRIES").unwrap_or(DEFAULT_WRITE_RETRIES),
+                ..Default::default()
+            },
+            inline_max_bytes: env_parse("INLINE_MAX_BYTES").unwrap_or(DEFAULT_INLINE_MAX_BYTES),
+            max_processing: env_parse("MAX_PROCESSING_SECS")
+                .filter(|secs| *secs > 0)
+                .map(std::time::Duration::from_secs),
+            strictness: std::env::var("DETECTION_STRICTNESS")
+                .ok()
+                .and_then(|value| Strictness::parse(&value))
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    /// Check the environment variables behind `from_env` that can't be
+    /// ignored when invalid, so a misconfiguration shows at startup rather
+    /// than as uploads quietly processed without it.
+    pub fn check_env() -> Result<(), String> {
+        if let Ok(value) = std::env::var("STOP_PATTERNS") {
+            parse_stop_patterns(&value).map_err(|e| format!("STOP_PATTERNS: {}", e))?;
+        }
+        // A call allowed the whole upload's time would be cut off by the
+        // overall deadline instead, failing the upload rather than the call
+        let options = Self::from_env();
+        if let (Some(call), Some(total)) = (options.llm_call_timeout, options.max_processing)
+            && call >= total
+        {
+            return Err(format!(
+                "LLM_CALL_TIMEOUT_SECS ({}) must be less than MAX_PROCESSING_SECS ({})",
+                call.as_secs(),
+                total.as_secs()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Apply a form field to the options. Returns `Ok(false)` for fields that
+    /// aren't options so callers can ignore them.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<bool, String> {
+        let value = value.trim();
+        match name {
+            "llm_url" => self.llm_url = non_empty(value),
+            "llm_model" => self.llm_model = non_empty(value),
+            "llm_seed" => self.llm_seed = parse_optional(name, value)?,
+            "original_positions" => self.original_positions = parse_bool(name, value)?,
+            "inline" => self.inline = parse_bool(name, value)?,
+            "llm_token_budget" => self.llm_token_budget = parse_optional(name, value)?,
+            "llm_sample_every" => {
+                self.llm_sample_every = parse_optional(name, value)?.filter(|every| *every > 0)
+            }
+            "llm_min_chapters" => self.llm_min_chapters = parse_optional(name, value)?,
+            "llm_classify" => self.llm_classify = parse_bool(name, value)?,
+            "llm_boundary_chars" => self.llm_boundary_chars = parse_optional(name, value)?,
+            "llm_anonymize" => self.llm_anonymize = parse_bool(name, value)?,
+            "llm_subjects" => self.llm_subjects = parse_bool(name, value)?,
+            "normalize_titles" => self.normalize_titles = parse_bool(name, value)?,
+            "strip_invisible" => self.strip_invisible = parse_bool(name, value)?,
+            "lossy_utf8" => self.lossy_utf8 = parse_bool(name, value)?,
+            "min_printable_ratio" => {
+                self.min_printable_ratio = parse_optional(name, value)?;
+                if self.min_printable_ratio.is_some_and(|ratio| !(0.0..=1.0).contains(&ratio)) {
+                    return Err(format!("'{}' must be between 0 and 1: {}", name, value));
+                }
+            }
+            "line_splits" => self.line_splits = parse_line_splits(value)?,
+            "dominant_pattern" => self.dominant_pattern = parse_bool(name, value)?,
+            "collapse_lists" => self.collapse_lists = parse_bool(name, value)?,
+            "merge_continuations" => self.merge_continuations = parse_bool(name, value)?,
+            "ambiguity_warnings" => self.ambiguity_warnings = parse_bool(name, value)?,
+            "form_feeds" => self.form_feeds = parse_bool(name, value)?,
+            "bilingual_titles" => {
+                self.bilingual_titles = match value.to_lowercase().as_str() {
+                    "both" | "" => BilingualTitles::Both,
+                    "latin" | "english" => BilingualTitles::Latin,
+                    "cjk" | "chinese" => BilingualTitles::Cjk,
+                    "off" | "false" => BilingualTitles::Off,
+                    _ => return Err(format!("Unsupported bilingual titles: {}", value)),
+                }
+            }
+            "parallel_detection" => self.parallel_detection = parse_bool(name, value)?,
+            "promote_title_lines" => self.promote_title_lines = parse_bool(name, value)?,
+            "strip_banner" => self.strip_banner = parse_bool(name, value)?,
+            "reorder_chapters" => self.reorder_chapters = parse_bool(name, value)?,
+            "skip_blank_lines" => self.skip_blank_lines = parse_bool(name, value)?,
+            "expected_chapters" => self.expected_chapters = parse_optional(name, value)?,
+            "strict" => self.strict = parse_bool(name, value)?,
+            "allow_single_chapter" => self.allow_single_chapter = parse_bool(name, value)?,
+            "front_matter" => {
+                self.front_matter = FrontMatter::parse(value)
+                    .ok_or_else(|| format!("Unsupported front matter strategy: {}", value))?
+            }
+            "single_marker_front_matter" => {
+                self.single_marker_front_matter = match value {
+                    "" => None,
+                    _ => Some(
+                        FrontMatter::parse(value)
+                            .ok_or_else(|| format!("Unsupported front matter strategy: {}", value))?,
+                    ),
+                }
+            }
+            "stop_patterns" => self.stop_patterns = parse_stop_patterns(value)?,
+            "title_rewrite" => self.title_rewrite = parse_title_rewrite(value)?,
+            "keywords" => self.keywords = parse_keywords(value)?,
+            "after_end" => {
+                self.after_end = match value.to_lowercase().as_str() {
+                    "discard" | "" => AfterEnd::Discard,
+                    "separate_section" | "separate" => AfterEnd::SeparateSection,
+                    _ => return Err(format!("Unsupported after_end strategy: {}", value)),
+                }
+            }
+            "min_chapter_chars" => self.min_chapter_chars = parse_optional(name, value)?,
+            "min_chapters" => self.min_chapters = parse_optional(name, value)?,
+            "max_chapters" => self.max_chapters = parse_optional(name, value)?.filter(|max| *max > 0),
+            "input_format" => {
+                self.input_format = match value.to_lowercase().as_str() {
+                    "auto" | "" => InputFormat::Auto,
+                    "text" => InputFormat::Text,
+                    "html" => InputFormat::Html,
+                    _ => return Err(format!("Unsupported input format: {}", value)),
+                }
+            }
+            "mode" => {
+                self.mode = match value.to_lowercase().as_str() {
+                    "prose" | "" => DetectionMode::Prose,
+                    "script" => DetectionMode::Script,
+                    "markdown" => DetectionMode::Markdown,
+                    _ => return Err(format!("Unsupported detection mode: {}", value)),
+                };
+                // Markdown chapters keep their formatting unless a later
+                // `content_format` field says otherwise
+                if self.mode == DetectionMode::Markdown {
+                    self.epub.content_format = ContentFormat::Markdown;
+                }
+            }
+            "strictness" => {
+                self.strictness = Strictness::parse(value)
+                    .ok_or_else(|| format!("Unsupported strictness: {}", value))?
+            }
+            "formats" => {
+                let mut formats = Vec::new();
+                for item in value.split(',').filter(|item| !item.trim().is_empty()) {
+                    let format = OutputFormat::parse(item)
+                        .ok_or_else(|| format!("Unsupported output format: {}", item.trim()))?;
+                    if !formats.contains(&format) {
+                        formats.push(format);
+                    }
+                }
+                self.formats = if formats.is_empty() { default_formats() } else { formats };
+            }
+            "epub_version" => {
+                self.epub.version = match value {
+                    "2" | "2.0" => EpubVersion::V2,
+                    "3" | "3.0" | "" => EpubVersion::V3,
+                    _ => return Err(format!("Unsupported EPUB version: {}", value)),
+                }
+            }
+            "content_format" => {
+                self.epub.content_format = match value.to_lowercase().as_str() {
+                    "text" | "" => ContentFormat::Text,
+                    "markdown" | "md" => ContentFormat::Markdown,
+                    "preformatted" | "pre" => ContentFormat::Preformatted,
+                    _ => return Err(format!("Unsupported content format: {}", value)),
+                }
+            }
+            "markdown_images" => {
+                self.epub.markdown_images = match value.to_lowercase().as_str() {
+                    "alt_text" | "alt" | "" => MarkdownImages::AltText,
+                    "drop" => MarkdownImages::Drop,
+                    "embed" => MarkdownImages::Embed,
+                    _ => return Err(format!("Unsupported Markdown image handling: {}", value)),
+                }
+            }
+            "paragraph_style" => {
+                self.epub.paragraph_style = match value.to_lowercase().as_str() {
+                    "auto" | "" => ParagraphStyle::Auto,
+                    "indent" => ParagraphStyle::Indent,
+                    "spaced" | "margin" => ParagraphStyle::Spaced,
+                    _ => return Err(format!("Unsupported paragraph style: {}", value)),
+                }
+            }
+            "manifest" => self.epub.manifest = parse_bool(name, value)?,
+            "bom" => self.epub.bom = parse_bool(name, value)?,
+            "footnotes" => self.epub.footnotes = parse_bool(name, value)?,
+            "normalize_punctuation" => self.epub.normalize_punctuation = parse_bool(name, value)?,
+            "normalize_spacing" => self.epub.normalize_spacing = parse_bool(name, value)?,
+            "title_page" => self.epub.title_page = parse_bool(name, value)?,
+            "disambiguate_titles" => self.epub.disambiguate_titles = parse_bool(name, value)?,
+            "spine_order" => self.epub.spine_order = parse_spine_order(value)?,
+            "date" => self.epub.date = parse_date(value)?,
+            "ncx" => self.epub.ncx = parse_bool(name, value)?,
+            "landmarks" => self.epub.landmarks = parse_bool(name, value)?,
+            "inline_toc" => self.epub.inline_toc = parse_bool(name, value)?,
+            "max_chapter_bytes" => self.epub.max_chapter_bytes = parse_optional(name, value)?,
+            "title_from_filename" => self.epub.title_from_filename = parse_bool(name, value)?,
+            "chapter_filenames" => {
+                self.epub.file_naming = match value.to_lowercase().as_str() {
+                    "index" | "" => ChapterFileNaming::Index,
+                    "title" => ChapterFileNaming::Title,
+                    _ => return Err(format!("Unsupported chapter file naming: {}", value)),
+                }
+            }
+            "toc_numbers" => {
+                self.epub.toc_numbering = match value.to_lowercase().as_str() {
+                    "arabic" | "true" | "1" | "yes" | "on" => TocNumbering::Arabic,
+                    "chinese" => TocNumbering::Chinese,
+                    "none" | "false" | "0" | "no" | "off" | "" => TocNumbering::None,
+                    _ => return Err(format!("Unsupported TOC numbering: {}", value)),
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+fn parse_line_splits(value: &str) -> Result<Option<Vec<usize>>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let mut lines: Vec<usize> = Vec::new();
+    for item in value.split(',') {
+        let line: usize = item
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid line number in 'line_splits': {}", item.trim()))?;
+        if line == 0 {
+            return Err("Line numbers in 'line_splits' start at 1".to_string());
+        }
+        if lines.last().is_some_and(|last| *last >= line) {
+            return Err(format!("'line_splits' must be in ascending order: {}", value));
+        }
+        lines.push(line);
+    }
+    Ok(Some(lines))
+}
+
+fn parse_spine_order(value: &str) -> Result<Option<Vec<usize>>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .split(',')
+        .map(|item| {
+            item.trim()
+                .parse()
+                .ok()
+                .filter(|number| *number > 0)
+                .ok_or_else(|| format!("Invalid chapter number in 'spine_order': {}", item.trim()))
+        })
+        .collect::<Result<Vec<usize>, String>>()
+        .map(Some)
+}
+
+/// Parse an EPUB date given as RFC 3339 or as a plain `YYYY-MM-DD` day
+/// (taken as midnight UTC).
+fn parse_date(value: &str) -> Result<Option<String>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(date) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(Some(format_date(date.to_utc())));
+    }
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|day| Some(format_date(day.and_time(chrono::NaiveTime::MIN).and_utc())))
+        .map_err(|_| format!("Invalid 'date' (expected YYYY-MM-DD or RFC 3339): {}", value))
+}
+
+fn format_date(date: chrono::DateTime<chrono::Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+fn parse_optional<T: std::str::FromStr>(name: &str, value: &str) -> Result<Option<T>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| format!("Invalid value for '{}': {}", name, value))
+}
+
+pub(crate) fn env_parse<T: std::str::FromStr>(key: &str) -> Option<T> {
+    std::env::var(key).ok().and_then(|value| value.trim().parse().ok())
+}
+
+pub(crate) fn env_flag(key: &str) -> bool {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| parse_bool(key, value.trim()).ok())
+        .unwrap_or(false)
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" | "" => Ok(false),
+        _ => Err(format!("Invalid boolean for '{}': {}", name, value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords_are_limited_in_number_and_length() {
+        assert_eq!(parse_keywords("Scene, 卷\nBook").unwrap(), ["Scene", "卷", "Book"]);
+        assert!(parse_keywords(&vec!["Scene"; MAX_KEYWORDS].join(",")).is_ok());
+        assert!(parse_keywords(&vec!["Scene"; MAX_KEYWORDS + 1].join(",")).is_err());
+        assert!(parse_keywords(&"x".repeat(KEYWORD_MAX_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn each_strictness_level_has_its_documented_parameters() {
+        let levels: Vec<(bool, usize, bool)> = [Strictness::Low, Strictness::Medium, Strictness::High]
+            .into_iter()
+            .map(|level| level.params())
+            .map(|params| (params.loose_patterns, params.min_chapter_chars, params.use_llm))
+            .collect();
+        assert_eq!(levels, [(true, 0, false), (true, 0, true), (false, 200, true)]);
+        assert_eq!(Strictness::parse(" HIGH "), Some(Strictness::High));
+        assert_eq!(Strictness::parse(""), Some(Strictness::Medium));
+        assert_eq!(Strictness::parse("strict"), None);
+    }
+
+    #[test]
+    fn multibyte_chapters_are_longer_in_bytes_than_in_characters() {
+        let chapter = Chapter {
+            title: "第一章".to_string(),
+            content: "天地玄黄 hello 🌏".to_string(),
+            start_pos: 0,
+            end_pos: 30,
+            role: SectionRole::default(),
+            linear: true,
+        };
+        // 4 Chinese characters of 3 bytes, 7 ASCII ones and a 4-byte emoji
+        assert_eq!(chapter.char_length(), 12);
+        assert_eq!(chapter.byte_length(), 4 * 3 + 7 + 4);
+
+        let sidecar = ChapterSidecar {
+            detected_chapters: vec![chapter.clone()],
+            chapters: vec![chapter],
+            epub: EpubOptions::default(),
+            formats: default_formats(),
+        };
+        let json = serde_json::to_value(&sidecar).unwrap();
+        for chapters in ["detected_chapters", "chapters"] {
+            assert_eq!(json[chapters][0]["byte_length"], 23);
+            assert_eq!(json[chapters][0]["char_length"], 12);
+        }
+        // The lengths are ignored when the sidecar is read back
+        let read: ChapterSidecar = serde_json::from_value(json).unwrap();
+        assert_eq!(read.chapters[0].content, "天地玄黄 hello 🌏");
+    }
 }
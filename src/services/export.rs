@@ -0,0 +1,77 @@
+use crate::models::{Chapter, EpubOptions};
+use crate::services::xhtml;
+
+/// Render chapters as a Markdown document with one level-1 heading per
+/// chapter.
+pub fn render_markdown(chapters: &[Chapter]) -> String {
+    chapters
+        .iter()
+        .map(|chapter| format!("# {}\n\n{}\n", chapter.title, chapter.content.trim()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Render chapters as one self-contained HTML page: embedded CSS, a table of
+/// contents linking to each chapter, then the chapters themselves. Chapter
+/// bodies are rendered the same way as in the EPUB.
+pub fn render_html(chapters: &[Chapter], options: &EpubOptions) -> String {
+    let mut toc = String::new();
+    let mut body = String::new();
+    let toc_titles = crate::services::chapterizer::toc_titles(chapters, options);
+    for (index, (chapter, toc_title)) in chapters.iter().zip(&toc_titles).enumerate() {
+        let id = xhtml::heading_id(index + 1, &chapter.title);
+        let title = html_escape::encode_text(&chapter.title);
+        toc.push_str(&format!(
+            "    <li><a href=\"#{}\">{}</a></li>\n",
+            id,
+            html_escape::encode_text(toc_title)
+        ));
+        body.push_str(&format!(
+            "<section>\n  <h1 id=\"{}\">{}</h1>\n  {}\n</section>\n",
+            id,
+            title,
+            xhtml::chapter_body(&chapter.content, index + 1, options, &Default::default())
+        ));
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\"/>\n<title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n<nav>\n  <h1>Contents</h1>\n  <ol>\n{}  </ol>\n</nav>\n{}</body>\n</html>\n",
+        html_escape::encode_text(crate::services::chapterizer::book_title(options)),
+        xhtml::stylesheet(options.paragraph_style, chapters),
+        toc,
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SectionRole;
+
+    fn chapter(title: &str, content: &str) -> Chapter {
+        Chapter {
+            title: title.to_string(),
+            content: content.to_string(),
+            start_pos: 0,
+            end_pos: content.len(),
+            role: SectionRole::Chapter,
+            linear: true,
+        }
+    }
+
+    #[test]
+    fn the_html_toc_links_to_each_chapter_anchor() {
+        let chapters = [chapter("The Road", "One."), chapter("Rock & Roll", "Two."), chapter("The Road", "Three.")];
+        let html = render_html(&chapters, &EpubOptions::default());
+
+        let toc = &html[html.find("<nav>").unwrap()..html.find("</nav>").unwrap()];
+        let anchor = regex::Regex::new(r##"<a href="#([^"]+)">"##).unwrap();
+        let links: Vec<&str> = anchor.captures_iter(toc).map(|captures| captures.get(1).unwrap().as_str()).collect();
+        assert_eq!(links, ["ch1-the-road", "ch2-rock-roll", "ch3-the-road"]);
+        for (link, chapter) in links.iter().zip(&chapters) {
+            let heading = format!("<h1 id=\"{}\">{}</h1>", link, html_escape::encode_text(&chapter.title));
+            assert!(html.contains(&heading), "{} missing from {}", heading, html);
+        }
+        assert!(toc.contains(">Rock &amp; Roll</a>"));
+    }
+}
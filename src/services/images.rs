@@ -0,0 +1,258 @@
+use crate::models::{Chapter, ContentFormat, EpubOptions, MarkdownImages};
+use anyhow::{Context, Result};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+// Hosts images may be fetched from, from IMAGE_ALLOWED_HOSTS
+// (comma-separated). Nothing is fetched when it's unset.
+static ALLOWED_HOSTS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    std::env::var("IMAGE_ALLOWED_HOSTS")
+        .map(|hosts| {
+            hosts
+                .split(',')
+                .map(|host| host.trim().trim_end_matches('.').to_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+});
+
+// Limit on fetching a single image
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Limit on fetching all images for one book; images still being fetched
+// then are left out
+const FETCH_ALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Images fetched at the same time for one book
+const CONCURRENT_FETCHES: usize = 8;
+
+// Largest image that is embedded
+const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+
+// Most images fetched for one book
+const MAX_IMAGES: usize = 100;
+
+/// An image fetched for embedding in the EPUB.
+#[derive(Debug, Clone)]
+pub struct EmbeddedImage {
+    /// Path inside the EPUB, relative to the chapter documents
+    pub href: String,
+    pub data: Vec<u8>,
+    pub media_type: String,
+}
+
+/// Embedded images by the URL they're referenced by in chapter content.
+pub type EmbeddedImages = HashMap<String, EmbeddedImage>;
+
+/// Fetch the remote images referenced by Markdown chapter content when
+/// `markdown_images` is `embed`. Images that can't be fetched are logged
+/// and left out; they're rendered as their alt text.
+pub async fn fetch_images(chapters: &[Chapter], options: &EpubOptions) -> EmbeddedImages {
+    let mut images = EmbeddedImages::new();
+    if options.content_format != ContentFormat::Markdown || options.markdown_images != MarkdownImages::Embed {
+        return images;
+    }
+
+    let mut urls: Vec<String> = Vec::new();
+    for chapter in chapters {
+        for url in image_urls(&chapter.content) {
+            if is_remote(&url) && !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+    }
+    if urls.len() > MAX_IMAGES {
+        tracing::warn!("Only embedding the first {} of {} images", MAX_IMAGES, urls.len());
+        urls.truncate(MAX_IMAGES);
+    }
+
+    let slots = Arc::new(Semaphore::new(CONCURRENT_FETCHES));
+    let mut fetches = tokio::task::JoinSet::new();
+    for (index, url) in urls.into_iter().enumerate() {
+        let slots = slots.clone();
+        fetches.spawn(async move {
+            let _permit = slots.acquire_owned().await;
+            let fetched = fetch_image(&url).await;
+            (index, url, fetched)
+        });
+    }
+
+    let mut fetched = Vec::new();
+    let deadline = tokio::time::Instant::now() + FETCH_ALL_TIMEOUT;
+    loop {
+        match tokio::time::timeout_at(deadline, fetches.join_next()).await {
+            Ok(Some(Ok((index, url, Ok(image))))) => fetched.push((index, url, image)),
+            Ok(Some(Ok((_, url, Err(e))))) => tracing::warn!("Not embedding image {}: {:#}", url, e),
+            Ok(Some(Err(e))) => tracing::warn!("Image fetch failed: {}", e),
+            Ok(None) => break,
+            Err(_) => {
+                tracing::warn!(
+                    "Not embedding {} images still being fetched after {:?}",
+                    fetches.len(),
+                    FETCH_ALL_TIMEOUT
+                );
+                fetches.abort_all();
+                break;
+            }
+        }
+    }
+
+    // Numbered in order of appearance, however the fetches finished
+    fetched.sort_by_key(|(index, ..)| *index);
+    for (_, url, (data, media_type)) in fetched {
+        let href = format!("images/img_{}.{}", images.len() + 1, extension(&media_type));
+        images.insert(url, EmbeddedImage { href, data, media_type });
+    }
+    images
+}
+
+/// URLs of the images in Markdown `content`, in order of appearance.
+pub fn image_urls(content: &str) -> Vec<String> {
+    pulldown_cmark::Parser::new(content)
+        .filter_map(|event| match event {
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Image { dest_url, .. }) => Some(dest_url.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether `url` is an absolute http(s) URL, which readers can load
+/// without the image being in the EPUB.
+pub fn is_remote(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Download one image, guarding against requests to hosts the server
+/// shouldn't reach: the host must be allow-listed and resolve to a public
+/// address, which the request is pinned to so a second lookup can't
+/// redirect it. HTTP redirects aren't followed.
+async fn fetch_image(url: &str) -> Result<(Vec<u8>, String)> {
+    let parsed = Url::parse(url).context("Invalid URL")?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        anyhow::bail!("Only http and https images are fetched");
+    }
+    let host = parsed.host_str().context("URL has no host")?.to_lowercase();
+    if !ALLOWED_HOSTS.contains(&host) {
+        anyhow::bail!("Host {} is not in IMAGE_ALLOWED_HOSTS", host);
+    }
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let address = tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port))
+        .await
+        .with_context(|| format!("Failed to resolve {}", host))?
+        .next()
+        .with_context(|| format!("{} has no addresses", host))?;
+    if !is_public(address.ip()) {
+        anyhow::bail!("{} resolves to non-public address {}", host, address.ip());
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(&host, address)
+        .build()?;
+    let mut response = client.get(parsed).send().await?.error_for_status()?;
+    let media_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_lowercase())
+        .unwrap_or_default();
+    // SVG can carry scripts, so only raster formats are embedded
+    if !matches!(media_type.as_str(), "image/png" | "image/jpeg" | "image/gif" | "image/webp") {
+        anyhow::bail!("Unsupported content type {:?}", media_type);
+    }
+    if response.content_length().is_some_and(|length| length > MAX_IMAGE_BYTES as u64) {
+        anyhow::bail!("Image is larger than {} bytes", MAX_IMAGE_BYTES);
+    }
+
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        data.extend_from_slice(&chunk);
+        if data.len() > MAX_IMAGE_BYTES {
+            anyhow::bail!("Image is larger than {} bytes", MAX_IMAGE_BYTES);
+        }
+    }
+    Ok((data, media_type))
+}
+
+// Loopback, private, link-local and other addresses that aren't on the
+// public internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // Shared address space (carrier-grade NAT)
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0
+                // Multicast, and reserved for future use
+                || a >= 224)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                let first = segments[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local and link-local
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80
+                    // NAT64, which can reach any IPv4 address
+                    || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0])
+            }
+        },
+    }
+}
+
+fn extension(media_type: &str) -> &'static str {
+    match media_type {
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        _ => "png",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_are_fetched_from() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "224.0.0.1",
+            "239.255.255.250",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:10.0.0.1",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+// How long finished jobs can still be looked up
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Most finished jobs kept; the oldest are dropped first
+const MAX_FINISHED_JOBS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        self != JobStatus::Running
+    }
+}
+
+/// Progress of LLM validation, shared between the processing task and the
+/// job registry. Steps are LLM calls; the total is planned up front, so
+/// steps past it (extra checks after merges) don't count.
+#[derive(Debug, Default)]
+pub struct Progress {
+    done: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl Progress {
+    /// Plan `total` steps.
+    pub fn start(&self, total: usize) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Record one step done.
+    pub fn advance(&self) {
+        let total = self.total.load(Ordering::Relaxed);
+        let _ = self
+            .done
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |done| (done < total).then_some(done + 1));
+    }
+
+    /// Mark every planned step done, e.g. when fewer were needed.
+    pub fn complete(&self) {
+        self.done.store(self.total.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Percentage of planned steps done; 0 until steps are planned.
+    pub fn percent(&self) -> u8 {
+        let total = self.total.load(Ordering::Relaxed);
+        let done = self.done.load(Ordering::Relaxed).min(total);
+        (done * 100).checked_div(total).unwrap_or(0) as u8
+    }
+}
+
+/// What a job's processing shares with the registry: the token that is
+/// cancelled when the job is and the progress it reports. Processing
+/// outside a job uses the default, which is never cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct JobHandle {
+    pub cancel: CancellationToken,
+    pub progress: Arc<Progress>,
+}
+
+/// A snapshot of a background job, as reported by the API.
+#[derive(Debug, Clone)]
+pub struct JobInfo {
+    pub status: JobStatus,
+    /// Percentage of LLM validation done; 100 once completed
+    pub progress: u8,
+    /// Response body of the finished upload, once completed
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+struct Job {
+    info: JobInfo,
+    handle: JobHandle,
+    /// When the job stopped running
+    finished_at: Option<Instant>,
+}
+
+/// In-memory registry of uploads being processed in the background.
+/// Finished jobs are forgotten after `FINISHED_JOB_TTL`, or sooner when
+/// more than `MAX_FINISHED_JOBS` have piled up.
+#[derive(Default)]
+pub struct JobRegistry {
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl JobRegistry {
+    /// Register a new running job. Returns its ID and the handle its
+    /// processing reports to.
+    pub fn start(&self) -> (String, JobHandle) {
+        let id = uuid::Uuid::new_v4().to_string();
+        let handle = JobHandle::default();
+        let job = Job {
+            info: JobInfo {
+                status: JobStatus::Running,
+                progress: 0,
+                result: None,
+                error: None,
+            },
+            handle: handle.clone(),
+            finished_at: None,
+        };
+        let mut jobs = self.jobs.lock().unwrap();
+        evict_finished(&mut jobs);
+        jobs.insert(id.clone(), job);
+        (id, handle)
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobInfo> {
+        self.jobs.lock().unwrap().get(id).map(|job| {
+            let mut info = job.info.clone();
+            if info.status == JobStatus::Running {
+                info.progress = job.handle.progress.percent();
+            }
+            info
+        })
+    }
+
+    /// Record the outcome of a job. A job that was cancelled stays cancelled.
+    pub fn finish(&self, id: &str, outcome: Result<serde_json::Value, String>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(id) else {
+            return;
+        };
+        if job.info.status == JobStatus::Cancelled {
+            return;
+        }
+        job.finished_at = Some(Instant::now());
+        job.info = match outcome {
+            Ok(result) => JobInfo {
+                status: JobStatus::Completed,
+                progress: 100,
+                result: Some(result),
+                error: None,
+            },
+            Err(error) => JobInfo {
+                status: JobStatus::Failed,
+                progress: job.handle.progress.percent(),
+                result: None,
+                error: Some(error),
+            },
+        };
+    }
+
+    /// Cancel a running job. Returns the job's status afterwards, or `None`
+    /// for an unknown ID; finished jobs are left as they are.
+    pub fn cancel(&self, id: &str) -> Option<JobStatus> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(id)?;
+        if !job.info.status.is_finished() {
+            job.handle.cancel.cancel();
+            job.finished_at = Some(Instant::now());
+            job.info.status = JobStatus::Cancelled;
+            job.info.progress = job.handle.progress.percent();
+        }
+        Some(job.info.status)
+    }
+}
+
+// Drop finished jobs past their TTL, then the oldest ones over the cap.
+// Running jobs are always kept.
+fn evict_finished(jobs: &mut HashMap<String, Job>) {
+    jobs.retain(|_, job| job.finished_at.is_none_or(|at| at.elapsed() < FINISHED_JOB_TTL));
+    let mut finished: Vec<(Instant, String)> = jobs
+        .iter()
+        .filter_map(|(id, job)| Some((job.finished_at?, id.clone())))
+        .collect();
+    if finished.len() <= MAX_FINISHED_JOBS {
+        return;
+    }
+    finished.sort();
+    for (_, id) in &finished[..finished.len() - MAX_FINISHED_JOBS] {
+        jobs.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancelled_jobs_stay_cancelled() {
+        let registry = JobRegistry::default();
+        let (id, handle) = registry.start();
+        assert_eq!(registry.cancel(&id), Some(JobStatus::Cancelled));
+        assert!(handle.cancel.is_cancelled());
+        registry.finish(&id, Ok(serde_json::json!({})));
+        assert_eq!(registry.get(&id).unwrap().status, JobStatus::Cancelled);
+        assert_eq!(registry.cancel("unknown"), None);
+    }
+
+    #[test]
+    fn only_the_newest_finished_jobs_are_kept() {
+        let registry = JobRegistry::default();
+        let running = registry.start().0;
+        let finished: Vec<String> = (0..MAX_FINISHED_JOBS + 5)
+            .map(|_| {
+                let id = registry.start().0;
+                registry.finish(&id, Err("failed".to_string()));
+                id
+            })
+            .collect();
+        registry.start();
+        assert_eq!(registry.jobs.lock().unwrap().len(), MAX_FINISHED_JOBS + 2);
+        assert!(registry.get(&running).is_some());
+        assert!(registry.get(&finished[0]).is_none());
+        assert!(registry.get(finished.last().unwrap()).is_some());
+    }
+}
@@ -1,63 +1,629 @@
-use crate::models::{Chapter, LLMResponse};
+use crate::models::{Chapter, LLMResponse, SubjectsResponse};
 use anyhow::Result;
 use reqwest;
 use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Request slots for each LLM host (and port), limiting how many requests
+/// are in flight to one host at a time. A client shares its slots with the
+/// clients made from it by `with_overrides`, so the limit holds across all
+/// uploads and jobs.
+#[derive(Default)]
+struct HostSlots {
+    // `None` for no limit
+    limit: Option<usize>,
+    slots: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostSlots {
+    fn new(limit: Option<usize>) -> Self {
+        HostSlots {
+            limit: limit.filter(|limit| *limit > 0),
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The semaphore limiting requests to the host of `api_url`, if there is
+    /// a limit.
+    fn for_url(&self, api_url: &str) -> Option<Arc<Semaphore>> {
+        let limit = self.limit?;
+        let url = reqwest::Url::parse(api_url).ok()?;
+        let host = format!(
+            "{}:{}",
+            url.host_str()?.to_lowercase(),
+            url.port_or_known_default().unwrap_or_default()
+        );
+        let mut slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+        Some(slots.entry(host).or_insert_with(|| Arc::new(Semaphore::new(limit))).clone())
+    }
+}
+
+/// Requests each LLM endpoint served and failed, for metrics.
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct EndpointStats {
+    pub served: u64,
+    pub failed: u64,
+}
+
+// Per-endpoint request counts across all clients, keyed by endpoint label
+// (`scheme://host:port` of a configured endpoint, or `OVERRIDE_LABEL`)
+static ENDPOINT_STATS: LazyLock<Mutex<HashMap<String, EndpointStats>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Label shared by all per-request `llm_url` overrides that aren't on a
+// configured endpoint, so callers can't add stats entries of their own
+const OVERRIDE_LABEL: &str = "override";
+
+fn record_request(label: &str, served: bool) {
+    let mut stats = ENDPOINT_STATS.lock().unwrap_or_else(|e| e.into_inner());
+    let entry = stats.entry(label.to_string()).or_default();
+    if served {
+        entry.served += 1;
+    } else {
+        entry.failed += 1;
+    }
+}
+
+/// Snapshot of how many requests each LLM endpoint has served and failed
+/// since the server started.
+pub fn endpoint_stats() -> HashMap<String, EndpointStats> {
+    ENDPOINT_STATS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+// Characters of an error response body kept in an `LLMError`
+const ERROR_EXCERPT_CHARS: usize = 200;
+
+/// Error returned when the LLM service answers with a non-2xx status, e.g.
+/// an HTML error page from a proxy. Such bodies are never parsed as a
+/// validation result.
+#[derive(Debug)]
+pub struct LLMError {
+    pub status: reqwest::StatusCode,
+    /// Start of the response body, with whitespace collapsed
+    pub excerpt: String,
+}
+
+impl std::fmt::Display for LLMError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LLM service returned {}", self.status)?;
+        if !self.excerpt.is_empty() {
+            write!(f, ": {}", self.excerpt)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LLMError {}
+
+/// Read the body of a successful response, or fail with an `LLMError` for
+/// any other status.
+async fn successful_body(response: reqwest::Response) -> Result<String> {
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        let collapsed = body.split_whitespace().collect::<Vec<_>>().join(" ");
+        let mut excerpt: String = collapsed.chars().take(ERROR_EXCERPT_CHARS).collect();
+        if collapsed.chars().count() > ERROR_EXCERPT_CHARS {
+            excerpt.push('…');
+        }
+        return Err(LLMError { status, excerpt }.into());
+    }
+    Ok(body)
+}
+
+/// Request and response shape spoken by the LLM endpoint, from
+/// `LLM_API_FORMAT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ApiFormat {
+    /// Ollama's `/api/generate`: a `prompt`, with the response body taken
+    /// as the answer
+    #[default]
+    Ollama,
+    /// OpenAI-compatible `/v1/chat/completions`: system and user
+    /// `messages`, answered in `choices[0].message.content`
+    OpenAi,
+}
+
+impl ApiFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "ollama" | "" => Some(ApiFormat::Ollama),
+            "openai" => Some(ApiFormat::OpenAi),
+            _ => None,
+        }
+    }
+
+    fn default_url(self) -> &'static str {
+        match self {
+            ApiFormat::Ollama => "http://localhost:11434/api/generate",
+            // Ollama's OpenAI-compatible endpoint
+            ApiFormat::OpenAi => "http://localhost:11434/v1/chat/completions",
+        }
+    }
+}
+
+// System message sent ahead of every prompt in the OpenAI format
+const SYSTEM_PROMPT: &str =
+    "You analyze the structure of books for chapter segmentation. Respond only with the JSON object requested, without commentary or code fences.";
+
+/// The JSON answer inside a chat completion response body.
+fn chat_content(body: &str) -> Result<String> {
+    let response: serde_json::Value =
+        serde_json::from_str(body).map_err(|e| anyhow::anyhow!("Invalid chat completion response: {}", e))?;
+    let content = response["choices"][0]["message"]["content"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("Chat completion response has no choices[0].message.content"))?
+        .trim();
+    // Models often wrap JSON in a Markdown code fence despite being asked not to
+    let content = match content.strip_prefix("```") {
+        Some(fenced) => fenced
+            .trim_start_matches("json")
+            .trim_end()
+            .trim_end_matches("```")
+            .trim(),
+        None => content,
+    };
+    Ok(content.to_string())
+}
+
+// Default number of retries of a failed request on the same endpoint
+const DEFAULT_RETRIES: u32 = 2;
+
+// Pause before the first retry of a failed request; doubled for each retry
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Whether a failed request may succeed if sent again: network errors and
+/// server-side or rate-limit statuses, but not other 4xx answers or
+/// responses that couldn't be parsed.
+fn is_transient(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<LLMError>() {
+        Some(e) => e.status.is_server_error() || e.status == reqwest::StatusCode::TOO_MANY_REQUESTS,
+        None => error.downcast_ref::<reqwest::Error>().is_some(),
+    }
+}
+
+/// Connection pool settings for the HTTP client used to reach the LLM.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PoolSettings {
+    /// Idle connections kept open per host (`LLM_POOL_MAX_IDLE_PER_HOST`)
+    pub max_idle_per_host: Option<usize>,
+    /// How long idle connections are kept (`LLM_POOL_IDLE_TIMEOUT_SECS`)
+    pub idle_timeout: Option<Duration>,
+    /// TCP keep-alive interval (`LLM_TCP_KEEPALIVE_SECS`)
+    pub tcp_keepalive: Option<Duration>,
+    /// Talk HTTP/2 without negotiation, for h2c backends (`LLM_HTTP2`)
+    pub http2_prior_knowledge: bool,
+}
+
+impl PoolSettings {
+    pub fn from_env() -> Self {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    /// Read the settings from variables looked up by name.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let parse = |key: &str| -> Option<u64> { var(key).and_then(|value| value.trim().parse().ok()) };
+        PoolSettings {
+            max_idle_per_host: parse("LLM_POOL_MAX_IDLE_PER_HOST").map(|n| n as usize),
+            idle_timeout: parse("LLM_POOL_IDLE_TIMEOUT_SECS").map(Duration::from_secs),
+            tcp_keepalive: parse("LLM_TCP_KEEPALIVE_SECS").map(Duration::from_secs),
+            http2_prior_knowledge: var("LLM_HTTP2").is_some_and(|value| {
+                matches!(value.trim().to_lowercase().as_str(), "true" | "1" | "yes" | "on")
+            }),
+        }
+    }
+
+    /// Build an HTTP client with these settings; unset ones keep reqwest's
+    /// defaults.
+    pub fn build_client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(max_idle) = self.max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(timeout) = self.idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(interval) = self.tcp_keepalive {
+            builder = builder.tcp_keepalive(interval);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        builder
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to build LLM HTTP client: {}", e))
+    }
+}
+
+/// `host:port` of a URL, with the scheme's default port filled in.
+fn host_port(url: &reqwest::Url) -> Option<String> {
+    Some(format!("{}:{}", url.host_str()?.to_lowercase(), url.port_or_known_default()?))
+}
+
+/// `scheme://host:port` of a URL, with the scheme's default port filled in.
+fn origin(url: &reqwest::Url) -> Option<String> {
+    Some(format!("{}://{}", url.scheme(), host_port(url)?))
+}
+
+/// The `host:port` pairs an `LLM_ALLOWED_HOSTS` entry allows: the given
+/// port, or the default http and https ports for a bare host.
+fn allowed_host_ports(entry: &str) -> Vec<String> {
+    match entry.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => vec![entry.to_string()],
+        _ => vec![format!("{}:80", entry), format!("{}:443", entry)],
+    }
+}
+
+/// An LLM API URL with the request slots of its host.
+#[derive(Clone)]
+struct Endpoint {
+    api_url: String,
+    // Names the endpoint in metrics and logs without its path, query or
+    // credentials
+    label: String,
+    // Limits concurrent requests to the endpoint's host
+    host_slots: Option<Arc<Semaphore>>,
+    // Whether the API key may be sent here; only configured endpoints get it
+    send_key: bool,
+}
+
+impl Endpoint {
+    fn new(api_url: String, label: String, send_key: bool, host_slots: &HostSlots) -> Self {
+        Endpoint {
+            host_slots: host_slots.for_url(&api_url),
+            label,
+            api_url,
+            send_key,
+        }
+    }
+
+    /// Wait for a free request slot on the endpoint's host. The slot is held
+    /// until the returned permit is dropped.
+    async fn acquire_slot(&self) -> Result<Option<tokio::sync::SemaphorePermit<'_>>> {
+        match &self.host_slots {
+            Some(slots) => Ok(Some(slots.acquire().await?)),
+            None => Ok(None),
+        }
+    }
+}
 
 pub struct LLMClient {
     client: reqwest::Client,
-    api_url: String,
-    api_key: String,
+    // Tried in order until one answers: the primary endpoint, then fallbacks
+    endpoints: Vec<Endpoint>,
+    host_slots: Arc<HostSlots>,
+    // Sent as a bearer token when set, to configured endpoints only
+    api_key: Option<String>,
+    model: String,
+    // Sampling seed, for reproducible results from backends that support it
+    seed: Option<u64>,
+    // Times a failed request is retried before moving on to the next endpoint
+    retries: u32,
+    // `host:port` pairs that per-request URL overrides may point at
+    allowed_hosts: Vec<String>,
+    // Origins of the configured endpoints, which overrides must match
+    // exactly to be sent the API key
+    key_origins: Vec<String>,
+    format: ApiFormat,
 }
 
 impl LLMClient {
     pub fn new() -> Result<Self> {
-        let api_key = std::env::var("LLM_API_KEY").unwrap_or_else(|_| "dummy_key".to_string()); // In production, make this required
-        let api_url = std::env::var("LLM_API_URL")
-            .unwrap_or_else(|_| "http://localhost:11434/api/generate".to_string()); // Using Ollama as default
+        let api_key = std::env::var("LLM_API_KEY")
+            .ok()
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty());
+        let format_name = std::env::var("LLM_API_FORMAT").unwrap_or_default();
+        let format = ApiFormat::parse(&format_name)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported LLM_API_FORMAT '{}'; use ollama or openai", format_name))?;
+        let api_url = std::env::var("LLM_API_URL").unwrap_or_else(|_| format.default_url().to_string());
+        let api_urls: Vec<String> = std::iter::once(api_url)
+            .chain(
+                std::env::var("LLM_FALLBACK_URLS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|url| url.trim().to_string())
+                    .filter(|url| !url.is_empty()),
+            )
+            .collect();
+
+        let mut client = Self::with_endpoints(
+            api_urls,
+            &std::env::var("LLM_ALLOWED_HOSTS").unwrap_or_default(),
+            api_key,
+            format,
+        )?;
+        if let Ok(model) = std::env::var("LLM_MODEL") {
+            client.model = model;
+        }
+        client.seed = std::env::var("LLM_SEED").ok().and_then(|value| value.trim().parse().ok());
+        client.retries = crate::models::env_parse("LLM_RETRIES").unwrap_or(DEFAULT_RETRIES);
+        client.limit_per_host(crate::models::env_parse("LLM_MAX_CONCURRENT_PER_HOST"));
+        Ok(client)
+    }
+
+    /// A client for `api_urls` (the primary endpoint, then fallbacks) using
+    /// the default model. `allowed_hosts` lists further hosts that overrides
+    /// may point at, in the `LLM_ALLOWED_HOSTS` format.
+    pub(crate) fn with_endpoints(
+        api_urls: Vec<String>,
+        allowed_hosts: &str,
+        api_key: Option<String>,
+        format: ApiFormat,
+    ) -> Result<Self> {
+        // Configured endpoints are always allowed; LLM_ALLOWED_HOSTS adds more
+        let mut allowed_hosts: Vec<String> = allowed_hosts
+            .split(',')
+            .map(|host| host.trim().to_lowercase())
+            .filter(|host| !host.is_empty())
+            .flat_map(|host| allowed_host_ports(&host))
+            .collect();
+        let configured: Vec<reqwest::Url> =
+            api_urls.iter().filter_map(|api_url| reqwest::Url::parse(api_url).ok()).collect();
+        allowed_hosts.extend(configured.iter().filter_map(host_port));
+        let key_origins: Vec<String> = configured.iter().filter_map(origin).collect();
+        let host_slots = Arc::new(HostSlots::default());
+        let endpoints = api_urls
+            .into_iter()
+            .map(|api_url| {
+                let label = reqwest::Url::parse(&api_url)
+                    .ok()
+                    .and_then(|url| origin(&url))
+                    .unwrap_or_else(|| "invalid URL".to_string());
+                Endpoint::new(api_url, label, true, &host_slots)
+            })
+            .collect();
 
         Ok(LLMClient {
-            client: reqwest::Client::new(),
-            api_url,
+            client: PoolSettings::from_env().build_client()?,
+            endpoints,
+            host_slots,
             api_key,
+            model: "llama2".to_string(),
+            seed: None,
+            retries: DEFAULT_RETRIES,
+            allowed_hosts,
+            key_origins,
+            format,
         })
     }
 
-    pub async fn validate_chapter(&self, chapter: &Chapter) -> Result<LLMResponse> {
-        let prompt = format!(
-            "Analyze this text segment in any language (including Chinese) and determine if it represents a complete chapter in a book.\n\nContent: {}\n\nRespond with JSON: {{\"is_valid\": boolean, \"suggested_title\": string or null, \"has_content_modified\": false, \"suggestions\": string or null}}",
-            chapter.content
-        );
+    /// Allow at most `limit` requests in flight to each LLM host (and port)
+    /// at a time, from this client and those made from it by
+    /// `with_overrides`. `None` removes the limit.
+    pub(crate) fn limit_per_host(&mut self, limit: Option<usize>) {
+        self.host_slots = Arc::new(HostSlots::new(limit));
+        for endpoint in &mut self.endpoints {
+            endpoint.host_slots = self.host_slots.for_url(&endpoint.api_url);
+        }
+    }
 
+    /// Build a client for a single request that talks to a different endpoint
+    /// and/or model, or samples with a different seed. The URL must use
+    /// http(s) and point at an allowed host and port so uploads can't be used
+    /// to make the server call arbitrary addresses. It replaces the primary
+    /// endpoint; the fallbacks stay. The API key is only sent to the override
+    /// when its scheme, host and port match a configured endpoint, so callers
+    /// can't have it delivered elsewhere.
+    pub fn with_overrides(
+        &self,
+        api_url: Option<&str>,
+        model: Option<&str>,
+        seed: Option<u64>,
+    ) -> Result<Self> {
+        let mut endpoints = self.endpoints.clone();
+        if let Some(url) = api_url {
+            let parsed = reqwest::Url::parse(url)
+                .map_err(|e| anyhow::anyhow!("Invalid LLM URL '{}': {}", url, e))?;
+            if parsed.scheme() != "http" && parsed.scheme() != "https" {
+                anyhow::bail!("LLM URL must use http or https");
+            }
+            let host = host_port(&parsed).unwrap_or_default();
+            if !self.allowed_hosts.contains(&host) {
+                anyhow::bail!("LLM host '{}' is not in the allow-list", host);
+            }
+            // Overrides on a configured endpoint count towards its stats
+            match origin(&parsed).filter(|origin| self.key_origins.contains(origin)) {
+                Some(origin) => endpoints[0] = Endpoint::new(url.to_string(), origin, true, &self.host_slots),
+                None => {
+                    let label = OVERRIDE_LABEL.to_string();
+                    endpoints[0] = Endpoint::new(url.to_string(), label, false, &self.host_slots);
+                }
+            }
+        }
+
+        Ok(LLMClient {
+            client: self.client.clone(),
+            endpoints,
+            host_slots: self.host_slots.clone(),
+            api_key: self.api_key.clone(),
+            model: model.map(str::to_string).unwrap_or_else(|| self.model.clone()),
+            seed: seed.or(self.seed),
+            retries: self.retries,
+            allowed_hosts: self.allowed_hosts.clone(),
+            key_origins: self.key_origins.clone(),
+            format: self.format,
+        })
+    }
+
+    /// Send a prompt and return the response body, trying each endpoint in
+    /// turn until one answers successfully. Transient failures are retried
+    /// on the same endpoint first. Fails with the last endpoint's error when
+    /// none answer.
+    async fn generate(&self, prompt: &str) -> Result<String> {
+        let mut last_error = None;
+        for (index, endpoint) in self.endpoints.iter().enumerate() {
+            match self.generate_with_retries(endpoint, prompt).await {
+                Ok(body) => {
+                    record_request(&endpoint.label, true);
+                    if index > 0 {
+                        tracing::info!("LLM request served by fallback endpoint {}", endpoint.label);
+                    }
+                    return Ok(body);
+                }
+                Err(e) => {
+                    record_request(&endpoint.label, false);
+                    if index + 1 < self.endpoints.len() {
+                        tracing::warn!("LLM endpoint {} failed, trying the next one: {}", endpoint.label, e);
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No LLM endpoint is configured")))
+    }
+
+    /// `generate_at`, retried up to `retries` times with exponential backoff
+    /// when the request fails to connect or the server answers with a 5xx or
+    /// 429 status.
+    async fn generate_with_retries(&self, endpoint: &Endpoint, prompt: &str) -> Result<String> {
+        let mut attempt = 0;
+        loop {
+            match self.generate_at(endpoint, prompt).await {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    attempt += 1;
+                    tracing::warn!(
+                        "LLM endpoint {} failed: {}; retrying ({} of {})",
+                        endpoint.label,
+                        e,
+                        attempt,
+                        self.retries
+                    );
+                    tokio::time::sleep(RETRY_DELAY * 2u32.pow((attempt - 1).min(6))).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send a prompt to one endpoint and return the model's answer.
+    async fn generate_at(&self, endpoint: &Endpoint, prompt: &str) -> Result<String> {
+        let body = match self.format {
+            ApiFormat::Ollama => json!({
+                "model": self.model,
+                "prompt": prompt,
+                "stream": false,
+                "options": self.sampling_options()
+            }),
+            ApiFormat::OpenAi => {
+                // Chat completions take sampling options at the top level
+                let mut body = self.sampling_options();
+                body["model"] = json!(self.model);
+                body["messages"] = json!([
+                    { "role": "system", "content": SYSTEM_PROMPT },
+                    { "role": "user", "content": prompt }
+                ]);
+                body["stream"] = json!(false);
+                body
+            }
+        };
         let mut request_builder = self
             .client
-            .post(&self.api_url)
+            .post(&endpoint.api_url)
             .header("Content-Type", "application/json")
-            .json(&json!({
-                "model": "llama2", // Default model, can be configured
-                "prompt": prompt,
-                "stream": false,
-                "options": {
-                    "temperature": 0.1
-                }
-            }));
+            .json(&body);
 
-        // Add authorization header if API key is provided and not dummy
-        if self.api_key != "dummy_key" {
-            request_builder = request_builder.header("Authorization", format!("Bearer {}", self.api_key));
+        // Backends without authentication (e.g. a local Ollama) get no header
+        if endpoint.send_key
+            && let Some(api_key) = &self.api_key
+        {
+            request_builder = request_builder.bearer_auth(api_key);
         }
 
+        let _slot = endpoint.acquire_slot().await?;
         let response = request_builder.send().await?;
 
-        let response_text = response.text().await?;
+        let body = successful_body(response).await?;
+        match self.format {
+            ApiFormat::Ollama => Ok(body),
+            ApiFormat::OpenAi => chat_content(&body),
+        }
+    }
+
+    /// Sampling options sent with every request.
+    fn sampling_options(&self) -> serde_json::Value {
+        let mut options = json!({ "temperature": 0.1 });
+        if let Some(seed) = self.seed {
+            options["seed"] = json!(seed);
+        }
+        options
+    }
+
+    /// Prompt for validating one chapter. With `classify`, the LLM is also
+    /// asked what kind of section the chapter is. With `anonymize`, only the
+    /// chapter's structural features are sent, not its text.
+    pub fn validation_prompt(chapter: &Chapter, classify: bool, anonymize: bool) -> String {
+        let content = if anonymize {
+            format!("(the text is withheld; only its structural features are given)\n{}", outline(&chapter.content))
+        } else {
+            chapter.content.clone()
+        };
+        if classify {
+            return format!(
+                "Analyze this text segment in any language (including Chinese) and determine if it represents a complete chapter in a book. Also classify what kind of section it is: \"chapter\" for the main story or body, \"preface\" for introductory material before it, \"appendix\" for supplementary material after it (notes, references, glossaries), or \"interlude\" for a short piece between chapters outside the main narrative.\n\nContent: {}\n\nRespond with JSON: {{\"is_valid\": boolean, \"suggested_title\": string or null, \"has_content_modified\": false, \"suggestions\": string or null, \"chapter_type\": \"chapter\" | \"preface\" | \"appendix\" | \"interlude\"}}",
+                content
+            );
+        }
+        format!(
+            "Analyze this text segment in any language (including Chinese) and determine if it represents a complete chapter in a book.\n\nContent: {}\n\nRespond with JSON: {{\"is_valid\": boolean, \"suggested_title\": string or null, \"has_content_modified\": false, \"suggestions\": string or null}}",
+            content
+        )
+    }
+
+    /// Prompt for checking the boundary between two chapters. With
+    /// `excerpt_chars`, only the whole sentences within that many characters
+    /// of the boundary are sent from each side. With `anonymize`, only the
+    /// structural features of both chapters are sent, not their text.
+    pub fn comparison_prompt(
+        chapter1: &Chapter,
+        chapter2: &Chapter,
+        excerpt_chars: Option<usize>,
+        anonymize: bool,
+        mid_dialogue: bool,
+    ) -> String {
+        let (first, second) = if anonymize {
+            (outline(&chapter1.content), outline(&chapter2.content))
+        } else {
+            match excerpt_chars {
+                Some(max_chars) => (
+                    crate::utils::text::tail_excerpt(&chapter1.content, max_chars).to_string(),
+                    crate::utils::text::head_excerpt(&chapter2.content, max_chars).to_string(),
+                ),
+                None => (chapter1.content.clone(), chapter2.content.clone()),
+            }
+        };
+        let withheld = if anonymize {
+            " The text of the segments is withheld; judge from the structural features given for each."
+        } else {
+            ""
+        };
+        let dialogue = if mid_dialogue {
+            " The first segment's last line opens a quotation it doesn't close, so this boundary most likely splits dialogue: answer is_valid false unless the second segment clearly starts a new chapter."
+        } else {
+            ""
+        };
+        format!(
+            "You are reviewing the boundary between two consecutive text segments in any language (including Chinese) that were automatically segmented as chapters. Determine if the segmentation is appropriate. A boundary that falls in the middle of a dialogue exchange is not appropriate: if the first segment ends with an unclosed quotation, or the second segment continues the same conversation (the same speaker's line, or a reply to the last line), answer is_valid false so the segments are merged.{}{}\n\nFirst segment: {}\n\nSecond segment: {}\n\nRespond with JSON: {{\"is_valid\": boolean, \"suggested_title\": string or null, \"has_content_modified\": false, \"suggestions\": string or null}}",
+            dialogue, withheld, first, second
+        )
+    }
+
+    pub async fn validate_chapter(&self, chapter: &Chapter, classify: bool, anonymize: bool) -> Result<LLMResponse> {
+        let prompt = Self::validation_prompt(chapter, classify, anonymize);
+        let response_text = self.generate(&prompt).await?;
 
-        // Parse the response - the LLM response might be in a different format
-        // depending on the API used (Ollama, OpenAI, etc.)
+        // Parse the response - `generate` has already unwrapped it from the
+        // API's envelope, but models don't always answer with valid JSON
         let llm_response: LLMResponse =
             serde_json::from_str(&response_text).unwrap_or(LLMResponse {
                 is_valid: true,
                 suggested_title: None,
                 has_content_modified: false,
                 suggestions: None,
+                chapter_type: None,
             });
 
         Ok(llm_response)
@@ -67,33 +633,12 @@ impl LLMClient {
         &self,
         chapter1: &Chapter,
         chapter2: &Chapter,
+        excerpt_chars: Option<usize>,
+        anonymize: bool,
+        mid_dialogue: bool,
     ) -> Result<LLMResponse> {
-        let prompt = format!(
-            "You are reviewing the boundary between two consecutive text segments in any language (including Chinese) that were automatically segmented as chapters. Determine if the segmentation is appropriate.\n\nFirst segment: {}\n\nSecond segment: {}\n\nRespond with JSON: {{\"is_valid\": boolean, \"suggested_title\": string or null, \"has_content_modified\": false, \"suggestions\": string or null}}",
-            chapter1.content, chapter2.content
-        );
-
-        let mut request_builder = self
-            .client
-            .post(&self.api_url)
-            .header("Content-Type", "application/json")
-            .json(&json!({
-                "model": "llama2",
-                "prompt": prompt,
-                "stream": false,
-                "options": {
-                    "temperature": 0.1
-                }
-            }));
-
-        // Add authorization header if API key is provided and not dummy
-        if self.api_key != "dummy_key" {
-            request_builder = request_builder.header("Authorization", format!("Bearer {}", self.api_key));
-        }
-
-        let response = request_builder.send().await?;
-
-        let response_text = response.text().await?;
+        let prompt = Self::comparison_prompt(chapter1, chapter2, excerpt_chars, anonymize, mid_dialogue);
+        let response_text = self.generate(&prompt).await?;
 
         let llm_response: LLMResponse =
             serde_json::from_str(&response_text).unwrap_or(LLMResponse {
@@ -101,8 +646,460 @@ impl LLMClient {
                 suggested_title: None,
                 has_content_modified: false,
                 suggestions: None,
+                chapter_type: None,
             });
 
         Ok(llm_response)
     }
+
+    /// Prompt for tagging the book's genres and subjects from its chapter
+    /// titles and a sample of its text.
+    pub fn subjects_prompt(titles: &[&str], sample: &str) -> String {
+        format!(
+            "Classify this book, which may be in any language (including Chinese), for a library catalog. Give at most {} short genre or subject tags in the book's language (e.g. \"Science fiction\", \"武侠\"), most relevant first.\n\nChapter titles: {}\n\nSample: {}\n\nRespond with JSON: {{\"subjects\": [string]}}",
+            MAX_SUBJECTS,
+            titles.join(" | "),
+            sample
+        )
+    }
+
+    /// Ask for the book's genres and subjects. Returns at most
+    /// `MAX_SUBJECTS` distinct tags; fails when the response can't be
+    /// parsed.
+    pub async fn suggest_subjects(&self, titles: &[&str], sample: &str) -> Result<Vec<String>> {
+        let prompt = Self::subjects_prompt(titles, sample);
+        let response_text = self.generate(&prompt).await?;
+        let response: SubjectsResponse = serde_json::from_str(&response_text)
+            .map_err(|e| anyhow::anyhow!("Invalid subjects response: {}", e))?;
+
+        let mut subjects: Vec<String> = Vec::new();
+        for subject in response.subjects {
+            let subject = subject.split_whitespace().collect::<Vec<_>>().join(" ");
+            if subject.is_empty()
+                || subject.chars().count() > MAX_SUBJECT_CHARS
+                || subjects.iter().any(|kept| kept.to_lowercase() == subject.to_lowercase())
+            {
+                continue;
+            }
+            subjects.push(subject);
+            if subjects.len() == MAX_SUBJECTS {
+                break;
+            }
+        }
+        Ok(subjects)
+    }
+}
+
+// Most subjects kept from the LLM's answer
+const MAX_SUBJECTS: usize = 5;
+
+// Longest subject kept, in characters; longer answers are descriptions
+// rather than tags
+const MAX_SUBJECT_CHARS: usize = 50;
+
+// Longest first or last line quoted in an anonymized prompt, in characters
+const OUTLINE_LINE_CHARS: usize = 60;
+
+/// Structural features of a chapter for anonymized prompts: its size, its
+/// first and last lines (clipped to `OUTLINE_LINE_CHARS`), and whether it
+/// starts or ends mid-sentence. Nothing else of the text is included.
+fn outline(content: &str) -> String {
+    use crate::utils::text::{ends_mid_sentence, head_excerpt, starts_mid_sentence, tail_excerpt};
+
+    let mut lines = content.lines().map(str::trim).filter(|line| !line.is_empty());
+    let first = lines.next().unwrap_or_default();
+    let last = lines.next_back().unwrap_or(first);
+    json!({
+        "characters": content.chars().count(),
+        "lines": content.lines().filter(|line| !line.trim().is_empty()).count(),
+        "first_line": head_excerpt(first, OUTLINE_LINE_CHARS),
+        "last_line": tail_excerpt(last, OUTLINE_LINE_CHARS),
+        "starts_mid_sentence": starts_mid_sentence(content),
+        "ends_mid_sentence": ends_mid_sentence(content),
+    })
+    .to_string()
+}
+
+/// Rough token count for a prompt: about four characters per token for
+/// Latin text and one token per character for CJK and other scripts.
+pub fn estimate_tokens(text: &str) -> usize {
+    let ascii = text.chars().filter(char::is_ascii).count();
+    let other = text.chars().count() - ascii;
+    ascii.div_ceil(4) + other
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockServer;
+
+    const VALID: &str = r#"{"is_valid": true, "suggested_title": null, "has_content_modified": false, "suggestions": null}"#;
+
+    async fn valid_server() -> MockServer {
+        MockServer::start(|_| (200, VALID.as_bytes().to_vec())).await
+    }
+
+    fn client_for(server: &MockServer, api_key: Option<&str>, format: ApiFormat) -> LLMClient {
+        let url = format!("{}/api/generate", server.url);
+        LLMClient::with_endpoints(vec![url], "", api_key.map(str::to_string), format).unwrap()
+    }
+
+    fn chapter(content: &str) -> Chapter {
+        Chapter {
+            title: "Chapter 1".to_string(),
+            content: content.to_string(),
+            start_pos: 0,
+            end_pos: content.len(),
+            role: Default::default(),
+            linear: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn override_is_used_instead_of_the_default_and_only_gets_the_key_on_a_configured_origin() {
+        let primary = valid_server().await;
+        let other = valid_server().await;
+        let other_host = other.url.trim_start_matches("http://").to_string();
+        let client = LLMClient::with_endpoints(
+            vec![format!("{}/api/generate", primary.url)],
+            &other_host,
+            Some("secret".to_string()),
+            ApiFormat::Ollama,
+        )
+        .unwrap();
+
+        // Same scheme, host and port as the configured endpoint: key is sent
+        let same_origin = client.with_overrides(Some(&format!("{}/other/path", primary.url)), None, None).unwrap();
+        same_origin.validate_chapter(&chapter("Text."), false, false).await.unwrap();
+        let requests = primary.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/other/path");
+        assert_eq!(requests[0].headers.get("authorization").map(String::as_str), Some("Bearer secret"));
+
+        // An allow-listed host on another port gets the request, not the key
+        let overridden = client
+            .with_overrides(Some(&format!("{}/api/generate", other.url)), Some("tenant-model"), None)
+            .unwrap();
+        overridden.validate_chapter(&chapter("Text."), false, false).await.unwrap();
+        assert_eq!(primary.requests().len(), 1);
+        let requests = other.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].json()["model"], "tenant-model");
+        assert!(!requests[0].headers.contains_key("authorization"));
+    }
+
+    #[test]
+    fn override_must_match_an_allowed_host_and_port() {
+        let client = LLMClient::with_endpoints(
+            vec!["http://llm.internal:11434/api/generate".to_string()],
+            "models.example.com",
+            None,
+            ApiFormat::Ollama,
+        )
+        .unwrap();
+        assert!(client.with_overrides(Some("http://llm.internal:11434/x"), None, None).is_ok());
+        assert!(client.with_overrides(Some("http://llm.internal:22/x"), None, None).is_err());
+        assert!(client.with_overrides(Some("https://models.example.com/v1"), None, None).is_ok());
+        assert!(client.with_overrides(Some("http://models.example.com:8080/v1"), None, None).is_err());
+        assert!(client.with_overrides(Some("http://evil.example.com/v1"), None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn fallback_serves_when_the_primary_fails_after_retries() {
+        let primary = MockServer::start(|_| (500, b"down".to_vec())).await;
+        let fallback = valid_server().await;
+        let mut client = LLMClient::with_endpoints(
+            vec![format!("{}/api/generate", primary.url), format!("{}/api/generate", fallback.url)],
+            "",
+            None,
+            ApiFormat::Ollama,
+        )
+        .unwrap();
+        client.retries = 1;
+
+        let response = client.validate_chapter(&chapter("Text."), false, false).await.unwrap();
+        assert!(response.is_valid);
+        assert_eq!(primary.requests().len(), 2);
+        assert_eq!(fallback.requests().len(), 1);
+
+        let stats = endpoint_stats();
+        assert_eq!(stats[&primary.url].failed, 1);
+        assert_eq!(stats[&fallback.url].served, 1);
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried_on_the_same_endpoint() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        let server = MockServer::start(move |_| {
+            if counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                (503, b"busy".to_vec())
+            } else {
+                (200, VALID.as_bytes().to_vec())
+            }
+        })
+        .await;
+        let client =
+            LLMClient::with_endpoints(vec![format!("{}/api/generate", server.url)], "", None, ApiFormat::Ollama).unwrap();
+        assert!(client.validate_chapter(&chapter("Text."), false, false).await.is_ok());
+        assert_eq!(server.requests().len(), 2);
+
+        // Client errors won't get better by asking again
+        let rejecting = MockServer::start(|_| (400, b"bad request".to_vec())).await;
+        let client =
+            LLMClient::with_endpoints(vec![format!("{}/api/generate", rejecting.url)], "", None, ApiFormat::Ollama)
+                .unwrap();
+        assert!(client.validate_chapter(&chapter("Text."), false, false).await.is_err());
+        assert_eq!(rejecting.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stats_are_keyed_by_origin_without_credentials_or_query() {
+        let server = valid_server().await;
+        let other = valid_server().await;
+        let with_secrets = server.url.replace("http://", "http://user:password@") + "/api/generate?key=secret";
+        let client = LLMClient::with_endpoints(
+            vec![with_secrets],
+            other.url.trim_start_matches("http://"),
+            None,
+            ApiFormat::Ollama,
+        )
+        .unwrap();
+        client.validate_chapter(&chapter("Text."), false, false).await.unwrap();
+        let overridden = client.with_overrides(Some(&format!("{}/any/path?q=1", other.url)), None, None).unwrap();
+        overridden.validate_chapter(&chapter("Text."), false, false).await.unwrap();
+
+        let stats = endpoint_stats();
+        assert_eq!(stats[&server.url].served, 1);
+        assert!(stats.contains_key(OVERRIDE_LABEL));
+        assert!(stats.keys().all(|key| !key.contains("secret") && !key.contains('?') && !key.contains("/any")));
+    }
+
+    #[tokio::test]
+    async fn error_responses_fail_with_their_status_and_an_excerpt() {
+        let page = "<html>\n<body>\n  <h1>502   Bad Gateway</h1>\n</body>\n</html>";
+        let server = MockServer::start(move |_| (502, page.as_bytes().to_vec())).await;
+        let mut client = client_for(&server, None, ApiFormat::Ollama);
+        client.retries = 0;
+
+        let error = client.validate_chapter(&chapter("Text."), false, false).await.unwrap_err();
+        let error = error.downcast_ref::<LLMError>().expect("an LLMError");
+        assert_eq!(error.status, reqwest::StatusCode::BAD_GATEWAY);
+        assert_eq!(error.excerpt, "<html> <body> <h1>502 Bad Gateway</h1> </body> </html>");
+
+        let long = MockServer::start(|_| (500, "x".repeat(1000).into_bytes())).await;
+        let mut client = client_for(&long, None, ApiFormat::Ollama);
+        client.retries = 0;
+        let error = client.validate_chapter(&chapter("Text."), false, false).await.unwrap_err();
+        let excerpt = &error.downcast_ref::<LLMError>().unwrap().excerpt;
+        assert_eq!(excerpt.chars().count(), ERROR_EXCERPT_CHARS + 1);
+        assert!(excerpt.ends_with('…'));
+    }
+
+    #[tokio::test]
+    async fn the_auth_header_is_only_sent_with_a_key() {
+        let server = valid_server().await;
+        client_for(&server, None, ApiFormat::Ollama)
+            .validate_chapter(&chapter("Text."), false, false)
+            .await
+            .unwrap();
+        client_for(&server, Some("dummy_key"), ApiFormat::Ollama)
+            .validate_chapter(&chapter("Text."), false, false)
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert!(!requests[0].headers.contains_key("authorization"));
+        assert_eq!(requests[1].headers.get("authorization").map(String::as_str), Some("Bearer dummy_key"));
+    }
+
+    #[tokio::test]
+    async fn anonymized_prompts_leave_out_the_chapter_body() {
+        let server = valid_server().await;
+        let client = client_for(&server, None, ApiFormat::Ollama);
+        let first = chapter("It began at dawn.\nThe secret ingredient was saffron.\nThey never spoke of it again.");
+        let second = chapter("Morning came.\nThe vault code was 4471.\nAnd so it ended");
+
+        client.validate_chapter(&first, true, true).await.unwrap();
+        client.compare_adjacent_chapters(&first, &second, None, true, false).await.unwrap();
+        client.validate_chapter(&first, false, false).await.unwrap();
+
+        let prompts: Vec<String> = server
+            .requests()
+            .iter()
+            .map(|request| request.json()["prompt"].as_str().unwrap().to_string())
+            .collect();
+        for prompt in &prompts[..2] {
+            assert!(!prompt.contains("saffron") && !prompt.contains("4471"), "{}", prompt);
+            assert!(!prompt.contains(&first.content) && !prompt.contains(&second.content));
+        }
+        assert!(prompts[0].contains("\"ends_mid_sentence\""));
+        assert!(prompts[2].contains("saffron"));
+    }
+
+    #[tokio::test]
+    async fn openai_requests_send_messages_and_read_the_first_choice() {
+        let content = format!("```json\n{}\n```", VALID.replace("null, \"has", "\"Chapter One\", \"has"));
+        let reply = json!({ "choices": [{ "message": { "role": "assistant", "content": content } }] });
+        let server = MockServer::start(move |_| (200, reply.to_string().into_bytes())).await;
+        let mut client = client_for(&server, None, ApiFormat::OpenAi);
+        client.model = "gpt-test".to_string();
+
+        let response = client.validate_chapter(&chapter("Text."), false, false).await.unwrap();
+        assert_eq!(response.suggested_title.as_deref(), Some("Chapter One"));
+
+        let body = server.requests()[0].json();
+        assert_eq!(body["model"], "gpt-test");
+        assert_eq!(body["stream"], false);
+        assert_eq!(body["temperature"], 0.1);
+        assert!(body.get("prompt").is_none());
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][0]["content"], SYSTEM_PROMPT);
+        assert_eq!(body["messages"][1]["role"], "user");
+        assert!(body["messages"][1]["content"].as_str().unwrap().contains("Text."));
+    }
+
+    #[test]
+    fn chat_content_requires_the_first_choice() {
+        assert_eq!(chat_content(r#"{"choices": [{"message": {"content": " {} "}}]}"#).unwrap(), "{}");
+        assert!(chat_content(r#"{"choices": []}"#).is_err());
+        assert!(chat_content("<html>").is_err());
+    }
+
+    #[test]
+    fn pool_settings_are_read_from_their_variables() {
+        let vars: HashMap<&str, &str> = [
+            ("LLM_POOL_MAX_IDLE_PER_HOST", "4"),
+            ("LLM_POOL_IDLE_TIMEOUT_SECS", " 30 "),
+            ("LLM_TCP_KEEPALIVE_SECS", "not a number"),
+            ("LLM_HTTP2", "Yes"),
+        ]
+        .into_iter()
+        .collect();
+        let settings = PoolSettings::from_vars(|key| vars.get(key).map(|value| value.to_string()));
+        assert_eq!(
+            settings,
+            PoolSettings {
+                max_idle_per_host: Some(4),
+                idle_timeout: Some(Duration::from_secs(30)),
+                tcp_keepalive: None,
+                http2_prior_knowledge: true,
+            }
+        );
+        assert_eq!(PoolSettings::from_vars(|_| None), PoolSettings::default());
+    }
+
+    // Serve "ok" on keep-alive connections, counting the connections opened
+    async fn keep_alive_server() -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 4096];
+                    while let Ok(read) = stream.read(&mut buffer).await
+                        && read > 0
+                    {
+                        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                        if stream.write_all(response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (url, connections)
+    }
+
+    #[tokio::test]
+    async fn clients_follow_the_pool_settings() {
+        // Idle connections are reused unless the pool keeps none
+        for (max_idle, expected_connections) in [(None, 1), (Some(0), 3)] {
+            let (url, connections) = keep_alive_server().await;
+            let client = PoolSettings { max_idle_per_host: max_idle, ..Default::default() }.build_client().unwrap();
+            for _ in 0..3 {
+                assert_eq!(client.get(&url).send().await.unwrap().text().await.unwrap(), "ok");
+            }
+            let opened = connections.load(std::sync::atomic::Ordering::SeqCst);
+            assert_eq!(opened, expected_connections, "max_idle_per_host = {:?}", max_idle);
+        }
+
+        // HTTP/2 prior knowledge starts with the HTTP/2 connection preface
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let preface = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut preface = [0u8; 24];
+            tokio::io::AsyncReadExt::read_exact(&mut stream, &mut preface).await.unwrap();
+            preface
+        });
+        let client = PoolSettings { http2_prior_knowledge: true, ..Default::default() }.build_client().unwrap();
+        let request = tokio::spawn(async move { client.get(&url).send().await });
+        assert_eq!(&preface.await.unwrap(), b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+        request.abort();
+    }
+
+    #[tokio::test]
+    async fn the_seed_is_sent_only_when_configured() {
+        let server = valid_server().await;
+        let client = client_for(&server, None, ApiFormat::Ollama);
+        client.validate_chapter(&chapter("Text."), false, false).await.unwrap();
+        let seeded = client.with_overrides(None, None, Some(42)).unwrap();
+        seeded.validate_chapter(&chapter("Text."), false, false).await.unwrap();
+        let requests = server.requests();
+        assert!(requests[0].json()["options"].get("seed").is_none());
+        assert_eq!(requests[1].json()["options"]["seed"], 42);
+        assert_eq!(requests[1].json()["options"]["temperature"], 0.1);
+
+        // Chat completions take it at the top level
+        let chat = MockServer::start(|_| {
+            let body = json!({ "choices": [{ "message": { "content": VALID } }] });
+            (200, body.to_string().into_bytes())
+        })
+        .await;
+        let seeded = client_for(&chat, None, ApiFormat::OpenAi).with_overrides(None, None, Some(7)).unwrap();
+        seeded.validate_chapter(&chapter("Text."), false, false).await.unwrap();
+        assert_eq!(chat.requests()[0].json()["seed"], 7);
+    }
+
+    #[tokio::test]
+    async fn requests_in_flight_to_a_host_never_exceed_the_limit() {
+        let server = MockServer::start_delayed(|_| (200, VALID.as_bytes().to_vec(), Duration::from_millis(50))).await;
+        let mut client = client_for(&server, None, ApiFormat::Ollama);
+        client.limit_per_host(Some(2));
+        // Clients made for a single request share the limit
+        let overridden = client.with_overrides(Some(&format!("{}/other", server.url)), None, None).unwrap();
+        let (client, overridden) = (Arc::new(client), Arc::new(overridden));
+
+        let calls: Vec<_> = (0..8)
+            .map(|n| {
+                let client = if n % 2 == 0 { client.clone() } else { overridden.clone() };
+                tokio::spawn(async move { client.validate_chapter(&chapter("Text."), false, false).await })
+            })
+            .collect();
+        for call in calls {
+            assert!(call.await.unwrap().unwrap().is_valid);
+        }
+        assert_eq!(server.requests().len(), 8);
+        assert_eq!(server.peak_in_flight(), 2);
+
+        // Without a limit they all go at once
+        let server = MockServer::start_delayed(|_| (200, VALID.as_bytes().to_vec(), Duration::from_millis(50))).await;
+        let client = Arc::new(client_for(&server, None, ApiFormat::Ollama));
+        let calls: Vec<_> = (0..8)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move { client.validate_chapter(&chapter("Text."), false, false).await })
+            })
+            .collect();
+        for call in calls {
+            call.await.unwrap().unwrap();
+        }
+        assert!(server.peak_in_flight() > 2);
+    }
 }
@@ -1,2 +1,7 @@
 pub mod chapterizer;
+pub mod export;
+pub mod images;
+pub mod jobs;
 pub mod llm;
+pub mod storage;
+pub mod xhtml;
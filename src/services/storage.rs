@@ -0,0 +1,258 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "s3")]
+pub use s3::S3Storage;
+
+/// Where generated files are kept, addressed by file name (e.g.
+/// `<id>.epub`). Handlers and the chapterizer only go through this trait,
+/// so outputs can live somewhere other than the local disk.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Store `data` under `name`, replacing any file already there. Readers
+    /// never see a partially stored file.
+    async fn put(&self, name: &str, data: &[u8]) -> Result<()>;
+    /// The file stored under `name`, or `None` if there is none.
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>>;
+    /// The file stored under `name` opened for reading, or `None` if there
+    /// is none. Backends that can't stream read the whole file with `get`.
+    async fn get_stream(&self, name: &str) -> Result<Option<StoredFile>> {
+        Ok(self.get(name).await?.map(|data| StoredFile {
+            len: data.len() as u64,
+            reader: Box::pin(std::io::Cursor::new(data)),
+        }))
+    }
+    async fn exists(&self, name: &str) -> Result<bool>;
+    /// Remove the file stored under `name`; a missing file isn't an error.
+    async fn delete(&self, name: &str) -> Result<()>;
+    /// Names of all stored files.
+    async fn list(&self) -> Result<Vec<String>>;
+    /// Where files are stored, for the startup log.
+    fn location(&self) -> String;
+
+    /// A URL clients can download `name` from directly, offered to them as
+    /// `file_name`, for backends that can serve files themselves. `None`
+    /// means downloads are served through this server.
+    fn download_url(&self, _name: &str, _file_name: &str) -> Option<String> {
+        None
+    }
+}
+
+/// A stored file being read.
+pub struct StoredFile {
+    /// Size in bytes
+    pub len: u64,
+    pub reader: Pin<Box<dyn tokio::io::AsyncRead + Send>>,
+}
+
+/// The storage backend configured by the environment: S3 when `S3_BUCKET`
+/// is set, otherwise the local `OUTPUT_DIR`. Fails if the backend can't be
+/// used, so a misconfiguration shows at startup rather than on the first
+/// upload.
+pub fn from_env() -> Result<Arc<dyn Storage>> {
+    if std::env::var("S3_BUCKET").is_ok_and(|bucket| !bucket.trim().is_empty()) {
+        #[cfg(feature = "s3")]
+        return Ok(Arc::new(S3Storage::from_env()?));
+        #[cfg(not(feature = "s3"))]
+        anyhow::bail!("S3_BUCKET is set, but this build has no S3 support; build with `--features s3`");
+    }
+
+    crate::utils::fs::ensure_output_dir()?;
+    Ok(Arc::new(LocalStorage::new(crate::utils::fs::output_dir())))
+}
+
+/// Files in a directory on the local disk.
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        LocalStorage { dir: dir.into() }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(name);
+        let data = data.to_vec();
+        tokio::task::spawn_blocking(move || crate::utils::fs::write_atomic(path, &data)).await?
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(name)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", name)),
+        }
+    }
+
+    async fn get_stream(&self, name: &str) -> Result<Option<StoredFile>> {
+        // Files are replaced by renaming, so an open file stays complete
+        let file = match tokio::fs::File::open(self.path(name)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", name)),
+        };
+        let len = file.metadata().await.with_context(|| format!("Failed to read {}", name))?.len();
+        Ok(Some(StoredFile { len, reader: Box::pin(file) }))
+    }
+
+    async fn exists(&self, name: &str) -> Result<bool> {
+        tokio::fs::try_exists(self.path(name))
+            .await
+            .with_context(|| format!("Failed to check for {}", name))
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(name)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to delete {}", name))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut entries = tokio::fs::read_dir(&self.dir)
+            .await
+            .with_context(|| format!("Failed to list {}", self.dir.display()))?;
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Skip files still being written and write probes
+            if entry.file_type().await?.is_file() && !name.ends_with(".tmp") && !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn location(&self) -> String {
+        self.dir.display().to_string()
+    }
+}
+
+// Pause before the first retry of a failed put; doubled for each retry after
+const PUT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// `put`, retried up to `retries` times with exponential backoff for
+/// storage that fails intermittently (e.g. network mounts).
+pub async fn put_with_retry(storage: &dyn Storage, name: &str, data: &[u8], retries: u32) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        match storage.put(name, data).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                tracing::warn!("Failed to store {}: {:#}; retrying ({} of {})", name, e, attempt, retries);
+                tokio::time::sleep(PUT_RETRY_DELAY * 2u32.pow((attempt - 1).min(6))).await;
+            }
+            Err(e) => return Err(e.context(format!("Failed to store {} after {} attempts", name, attempt + 1))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MemoryStorage;
+    use tokio::io::AsyncReadExt;
+
+    pub(super) async fn round_trip(storage: &dyn Storage) {
+        let data = b"PK\x03\x04 some epub bytes".repeat(1000);
+        storage.put("book.epub", &data).await.unwrap();
+        assert!(storage.exists("book.epub").await.unwrap());
+        assert_eq!(storage.get("book.epub").await.unwrap(), Some(data.clone()));
+
+        let mut file = storage.get_stream("book.epub").await.unwrap().unwrap();
+        assert_eq!(file.len, data.len() as u64);
+        let mut read = Vec::new();
+        file.reader.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+
+        assert_eq!(storage.list().await.unwrap(), ["book.epub"]);
+        storage.delete("book.epub").await.unwrap();
+        assert!(storage.get("book.epub").await.unwrap().is_none());
+        assert!(storage.get_stream("book.epub").await.unwrap().is_none());
+        storage.delete("book.epub").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_storage_round_trip() {
+        let dir = std::env::temp_dir().join(format!("duanzh-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        round_trip(&LocalStorage::new(&dir)).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streaming_falls_back_to_get() {
+        round_trip(&MemoryStorage::default()).await;
+    }
+
+    // Storage whose first `failures` puts fail
+    #[derive(Default)]
+    struct FlakyStorage {
+        inner: MemoryStorage,
+        failures: u32,
+        puts: std::sync::atomic::AtomicU32,
+    }
+
+    #[async_trait]
+    impl Storage for FlakyStorage {
+        async fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+            let attempt = self.puts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if attempt < self.failures {
+                anyhow::bail!("transient failure {}", attempt + 1);
+            }
+            self.inner.put(name, data).await
+        }
+        async fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+            self.inner.get(name).await
+        }
+        async fn exists(&self, name: &str) -> Result<bool> {
+            self.inner.exists(name).await
+        }
+        async fn delete(&self, name: &str) -> Result<()> {
+            self.inner.delete(name).await
+        }
+        async fn list(&self) -> Result<Vec<String>> {
+            self.inner.list().await
+        }
+        fn location(&self) -> String {
+            "flaky".to_string()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_puts_are_retried_with_backoff_until_the_retries_run_out() {
+        let storage = FlakyStorage { failures: 2, ..Default::default() };
+        let started = tokio::time::Instant::now();
+        put_with_retry(&storage, "book.epub", b"epub", 3).await.unwrap();
+        assert_eq!(storage.puts.load(std::sync::atomic::Ordering::SeqCst), 3);
+        assert_eq!(storage.get("book.epub").await.unwrap().as_deref(), Some(&b"epub"[..]));
+        // Waited 100ms, then 200ms
+        assert_eq!(started.elapsed(), PUT_RETRY_DELAY * 3);
+
+        let storage = FlakyStorage { failures: 5, ..Default::default() };
+        let error = put_with_retry(&storage, "book.epub", b"epub", 3).await.unwrap_err();
+        assert_eq!(storage.puts.load(std::sync::atomic::Ordering::SeqCst), 4);
+        assert_eq!(
+            format!("{:#}", error),
+            "Failed to store book.epub after 4 attempts: transient failure 4"
+        );
+        assert!(storage.list().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,231 @@
+use super::Storage;
+use crate::models::env_flag;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::{StatusCode, Url};
+use rusty_s3::actions::ListObjectsV2;
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+use std::time::Duration;
+
+// How long signed request URLs stay valid
+const SIGNED_URL_EXPIRY: Duration = Duration::from_secs(15 * 60);
+
+// Limit on a single request to the object store
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Files stored as objects in an S3-compatible bucket (AWS S3, MinIO, ...).
+/// Requests are signed with query-string authentication and sent with the
+/// server's HTTP client.
+pub struct S3Storage {
+    bucket: Bucket,
+    credentials: Option<Credentials>,
+    /// Prepended to every file name to form its object key
+    prefix: String,
+    /// Redirect downloads to a signed bucket URL instead of proxying them
+    redirect_downloads: bool,
+    client: reqwest::Client,
+}
+
+impl S3Storage {
+    /// Configure the bucket from `S3_BUCKET`, `S3_ENDPOINT`, `S3_REGION`,
+    /// `S3_PREFIX`, `S3_PATH_STYLE` and `S3_REDIRECT_DOWNLOADS`, with
+    /// credentials from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+    pub fn from_env() -> Result<Self> {
+        let env = |key: &str| std::env::var(key).ok().map(|value| value.trim().to_string());
+
+        let name = env("S3_BUCKET").context("S3_BUCKET is not set")?;
+        let region = env("S3_REGION").unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = env("S3_ENDPOINT").unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        let endpoint: Url = endpoint
+            .parse()
+            .with_context(|| format!("S3_ENDPOINT is not a valid URL: {}", endpoint))?;
+        // MinIO and most self-hosted stores only support path-style URLs
+        let style = if env_flag("S3_PATH_STYLE") { UrlStyle::Path } else { UrlStyle::VirtualHost };
+        let bucket = Bucket::new(endpoint, style, name, region).context("Invalid S3 bucket configuration")?;
+
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        Ok(S3Storage {
+            bucket,
+            credentials: Credentials::from_env(),
+            prefix: env("S3_PREFIX").unwrap_or_default(),
+            redirect_downloads: env_flag("S3_REDIRECT_DOWNLOADS"),
+            client,
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    /// Send a signed request, failing on any status but success and those
+    /// in `allowed`. Returns the response for the caller to inspect.
+    async fn send(&self, request: reqwest::RequestBuilder, allowed: &[StatusCode]) -> Result<reqwest::Response> {
+        let response = request.send().await.context("S3 request failed")?;
+        let status = response.status();
+        if status.is_success() || allowed.contains(&status) {
+            return Ok(response);
+        }
+        let body = response.text().await.unwrap_or_default();
+        anyhow::bail!("S3 request failed with {}: {}", status, body.trim())
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        let key = self.key(name);
+        let url = self.bucket.put_object(self.credentials.as_ref(), &key).sign(SIGNED_URL_EXPIRY);
+        self.send(self.client.put(url).body(data.to_vec()), &[])
+            .await
+            .with_context(|| format!("Failed to store {}", name))?;
+        Ok(())
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let key = self.key(name);
+        let url = self.bucket.get_object(self.credentials.as_ref(), &key).sign(SIGNED_URL_EXPIRY);
+        let response = self
+            .send(self.client.get(url), &[StatusCode::NOT_FOUND])
+            .await
+            .with_context(|| format!("Failed to read {}", name))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.bytes().await?.to_vec()))
+    }
+
+    async fn exists(&self, name: &str) -> Result<bool> {
+        let key = self.key(name);
+        let url = self.bucket.head_object(self.credentials.as_ref(), &key).sign(SIGNED_URL_EXPIRY);
+        let response = self
+            .send(self.client.head(url), &[StatusCode::NOT_FOUND])
+            .await
+            .with_context(|| format!("Failed to check for {}", name))?;
+        Ok(response.status() != StatusCode::NOT_FOUND)
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let key = self.key(name);
+        let url = self.bucket.delete_object(self.credentials.as_ref(), &key).sign(SIGNED_URL_EXPIRY);
+        self.send(self.client.delete(url), &[StatusCode::NOT_FOUND])
+            .await
+            .with_context(|| format!("Failed to delete {}", name))?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut continuation = None;
+        loop {
+            let mut action = self.bucket.list_objects_v2(self.credentials.as_ref());
+            if !self.prefix.is_empty() {
+                action.with_prefix(self.prefix.as_str());
+            }
+            if let Some(token) = &continuation {
+                action.with_continuation_token(String::clone(token));
+            }
+            let url = action.sign(SIGNED_URL_EXPIRY);
+            let body = self
+                .send(self.client.get(url), &[])
+                .await
+                .context("Failed to list stored files")?
+                .bytes()
+                .await?;
+            let listing = ListObjectsV2::parse_response(&body).context("Invalid S3 listing")?;
+
+            names.extend(
+                listing
+                    .contents
+                    .into_iter()
+                    .filter_map(|object| object.key.strip_prefix(&self.prefix).map(str::to_string)),
+            );
+            continuation = listing.next_continuation_token;
+            if continuation.is_none() {
+                return Ok(names);
+            }
+        }
+    }
+
+    fn location(&self) -> String {
+        self.bucket
+            .object_url(&self.prefix)
+            .map_or_else(|_| self.bucket.base_url().to_string(), |url| url.to_string())
+    }
+
+    fn download_url(&self, name: &str, file_name: &str) -> Option<String> {
+        if !self.redirect_downloads {
+            return None;
+        }
+        let key = self.key(name);
+        let mut action = self.bucket.get_object(self.credentials.as_ref(), &key);
+        action
+            .query_mut()
+            .insert("response-content-disposition", format!("attachment; filename=\"{}\"", file_name));
+        Some(action.sign(SIGNED_URL_EXPIRY).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockServer;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    // A bucket that keeps objects in memory and answers path-style requests
+    async fn mock_bucket() -> MockServer {
+        let objects: Arc<Mutex<HashMap<String, Vec<u8>>>> = Default::default();
+        MockServer::start(move |request| {
+            let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+            assert!(query.contains("X-Amz-Signature="), "unsigned request: {}", request.path);
+            let key = path.trim_start_matches("/test-bucket").trim_start_matches('/').to_string();
+            let mut objects = objects.lock().unwrap();
+            match request.method.as_str() {
+                "GET" if query.contains("list-type=2") => {
+                    let contents: String = objects
+                        .iter()
+                        .map(|(key, data)| {
+                            format!(
+                                "<Contents><Key>{}</Key><ETag>\"x\"</ETag>\
+                                 <LastModified>2024-01-01T00:00:00.000Z</LastModified><Size>{}</Size></Contents>",
+                                key,
+                                data.len()
+                            )
+                        })
+                        .collect();
+                    let body = format!("<ListBucketResult>{}</ListBucketResult>", contents);
+                    (200, body.into_bytes())
+                }
+                "PUT" => {
+                    objects.insert(key, request.body.clone());
+                    (200, Vec::new())
+                }
+                "GET" | "HEAD" => match objects.get(&key) {
+                    Some(data) => (200, data.clone()),
+                    None => (404, b"<Error><Code>NoSuchKey</Code></Error>".to_vec()),
+                },
+                "DELETE" => {
+                    objects.remove(&key);
+                    (204, Vec::new())
+                }
+                _ => (405, Vec::new()),
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn s3_storage_round_trip() {
+        let server = mock_bucket().await;
+        let bucket = Bucket::new(server.url.parse().unwrap(), UrlStyle::Path, "test-bucket", "us-east-1").unwrap();
+        let storage = S3Storage {
+            bucket,
+            credentials: Some(Credentials::new("access", "secret")),
+            prefix: "books/".to_string(),
+            redirect_downloads: false,
+            client: reqwest::Client::new(),
+        };
+        super::super::tests::round_trip(&storage).await;
+        assert!(server.requests().iter().any(|request| request.path.starts_with("/test-bucket/books/book.epub?")));
+    }
+}
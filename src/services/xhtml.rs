@@ -0,0 +1,376 @@
+use crate::models::{Chapter, ContentFormat, EpubOptions, MarkdownImages, ParagraphStyle};
+use crate::services::images::EmbeddedImages;
+use regex::Regex;
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+// Tags allowed through the sanitizer, from SANITIZE_ALLOWED_TAGS
+// (comma-separated). `None` uses ammonia's default allow-list.
+static ALLOWED_TAGS: LazyLock<Option<Vec<String>>> = LazyLock::new(|| {
+    std::env::var("SANITIZE_ALLOWED_TAGS").ok().map(|tags| {
+        tags.split(',')
+            .map(|tag| tag.trim().to_lowercase())
+            // Script and style content is always removed
+            .filter(|tag| !tag.is_empty() && tag != "script" && tag != "style")
+            .collect()
+    })
+});
+
+// A footnote marker: a bracketed number or a dagger
+static FOOTNOTE_MARKER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[\d+\]|†|‡").expect("valid footnote marker regex"));
+
+// A line of footnote text, starting with its marker
+static FOOTNOTE_LINE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(\[\d+\]|†|‡)\s*(.+)$").expect("valid footnote line regex")
+});
+
+// HTML void elements, which XHTML requires to be self-closed
+static VOID_ELEMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"<(area|br|col|embed|hr|img|input|source|track|wbr)((?:\s(?:[^>"/]|"[^"]*")*)?)\s*/?>"#)
+        .expect("valid void element regex")
+});
+
+/// Render a chapter as a complete XHTML document for the EPUB. `number` is
+/// the chapter's 1-based position in the book, used for the heading id.
+/// Markdown images found in `images` link to their embedded copy.
+pub fn chapter_document(chapter: &Chapter, number: usize, options: &EpubOptions, images: &EmbeddedImages) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n<head>\n  <title>{}</title>\n  <link rel=\"stylesheet\" type=\"text/css\" href=\"stylesheet.css\"/>\n</head>\n<body{}>\n  <h1 id=\"{}\">{}</h1>\n  {}\n</body>\n</html>",
+        html_escape::encode_text(&chapter.title),
+        chapter.role.epub_type().map(|term| format!(" epub:type=\"{}\"", term)).unwrap_or_default(),
+        heading_id(number, &chapter.title),
+        html_escape::encode_text(&chapter.title),
+        chapter_body(&chapter.content, number, options, images)
+    )
+}
+
+/// Styles for the generated title page, added to the stylesheet when the
+/// EPUB has one.
+pub const TITLE_PAGE_CSS: &str = ".title-page { text-align: center; text-indent: 0; margin-top: 30%; }\n\
+.title-page h1 { font-size: 2em; margin-bottom: 1em; }\n\
+.title-page p { text-indent: 0; margin: 0.5em 0; }\n\
+.title-page .date { font-size: 0.9em; color: #666; }\n";
+
+/// Render the title page showing the book's title, author and date.
+pub fn title_page_document(title: &str, author: &str, date: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n<head>\n  <title>{}</title>\n  <link rel=\"stylesheet\" type=\"text/css\" href=\"stylesheet.css\"/>\n</head>\n<body>\n  <section class=\"title-page\" epub:type=\"titlepage\">\n    <h1>{}</h1>\n    <p class=\"author\">{}</p>\n    <p class=\"date\">{}</p>\n  </section>\n</body>\n</html>",
+        html_escape::encode_text(title),
+        html_escape::encode_text(title),
+        html_escape::encode_text(author),
+        html_escape::encode_text(date)
+    )
+}
+
+/// Stable id for a chapter heading, for deep links: `ch3` or
+/// `ch3-the-title`. The number keeps ids unique and the `ch` prefix makes
+/// them valid XML names.
+pub fn heading_id(number: usize, title: &str) -> String {
+    let slug = crate::utils::title::slugify(title);
+    if slug.is_empty() {
+        format!("ch{}", number)
+    } else {
+        format!("ch{}-{}", number, slug)
+    }
+}
+
+/// The stylesheet for the given paragraph style. With `Auto`, the style
+/// follows the script the chapters are written in.
+pub fn stylesheet(style: ParagraphStyle, chapters: &[Chapter]) -> String {
+    let style = match style {
+        ParagraphStyle::Auto => {
+            let text = chapters.iter().flat_map(|chapter| chapter.content.chars());
+            if crate::utils::text::is_mostly_cjk(text) {
+                ParagraphStyle::Indent
+            } else {
+                ParagraphStyle::Spaced
+            }
+        }
+        style => style,
+    };
+    let paragraph = match style {
+        ParagraphStyle::Indent | ParagraphStyle::Auto => "p { margin: 0; text-indent: 2em; }",
+        ParagraphStyle::Spaced => "p { margin: 0 0 1em 0; text-indent: 0; }",
+    };
+    format!("{}\n", paragraph)
+}
+
+/// Render chapter content as XHTML body markup. `number` is the chapter's
+/// 1-based position in the book, used to keep footnote ids unique.
+pub fn chapter_body(content: &str, number: usize, options: &EpubOptions, images: &EmbeddedImages) -> String {
+    match options.content_format {
+        ContentFormat::Text if options.footnotes => {
+            footnoted_body(content, number).unwrap_or_else(|| plain_text_body(content))
+        }
+        ContentFormat::Text => plain_text_body(content),
+        ContentFormat::Markdown => sanitize(&markdown_to_html(content, images, options.markdown_images)),
+        ContentFormat::Preformatted => preformatted_body(content),
+    }
+}
+
+fn preformatted_body(content: &str) -> String {
+    // pre-wrap keeps whitespace but lets long lines wrap on small screens
+    format!(
+        "<pre style=\"white-space: pre-wrap\">{}</pre>",
+        html_escape::encode_text(content)
+    )
+}
+
+fn plain_text_body(content: &str) -> String {
+    // Convert newlines to paragraph breaks for better formatting
+    content
+        .split("\n\n") // Split by double newlines (paragraphs)
+        .map(|para| {
+            let para_trimmed = para.trim();
+            if !para_trimmed.is_empty() {
+                format!("<p>{}</p>", html_escape::encode_text(para_trimmed))
+            } else {
+                String::new()
+            }
+        })
+        .filter(|s| !s.is_empty()) // Remove empty paragraphs
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Render plain text with its footnote markers linked to the footnotes,
+/// which go at the end as EPUB footnotes. Paragraphs starting with a marker
+/// hold the footnote text. Returns `None` when no marker in the text has
+/// footnote text to link to.
+fn footnoted_body(content: &str, number: usize) -> Option<String> {
+    let mut paragraphs = Vec::new();
+    let mut notes: Vec<(&str, String)> = Vec::new();
+    for para in content.split("\n\n").map(str::trim).filter(|para| !para.is_empty()) {
+        if !para.lines().next().is_some_and(|line| FOOTNOTE_LINE.is_match(line)) {
+            paragraphs.push(para);
+            continue;
+        }
+        for line in para.lines().map(str::trim) {
+            match FOOTNOTE_LINE.captures(line) {
+                Some(captures) => {
+                    let marker = captures.get(1).map_or("", |m| m.as_str());
+                    notes.push((marker, captures[2].to_string()));
+                }
+                // Lines without a marker continue the previous note
+                None => {
+                    if let Some((_, text)) = notes.last_mut() {
+                        text.push(' ');
+                        text.push_str(line);
+                    }
+                }
+            }
+        }
+    }
+
+    // The first note defined for a marker wins
+    let index_of = |marker: &str| notes.iter().position(|(defined, _)| *defined == marker);
+    let referenced = paragraphs.iter().any(|para| {
+        FOOTNOTE_MARKER
+            .find_iter(para)
+            .any(|marker| index_of(marker.as_str()).is_some())
+    });
+    if !referenced {
+        return None;
+    }
+
+    let mut linked = HashSet::new();
+    let mut html = Vec::new();
+    for para in paragraphs {
+        // Markers contain nothing that escaping changes
+        let escaped = html_escape::encode_text(para);
+        let body = FOOTNOTE_MARKER.replace_all(&escaped, |captures: &regex::Captures| {
+            let marker = &captures[0];
+            let Some(index) = index_of(marker) else {
+                return marker.to_string();
+            };
+            // Only the first reference carries the id the footnote links back to
+            let id = if linked.insert(index) {
+                format!(" id=\"ch{}-fnref{}\"", number, index + 1)
+            } else {
+                String::new()
+            };
+            format!(
+                "<a epub:type=\"noteref\" href=\"#ch{}-fn{}\"{}>{}</a>",
+                number,
+                index + 1,
+                id,
+                marker
+            )
+        });
+        html.push(format!("<p>{}</p>", body));
+    }
+    for (index, (marker, text)) in notes.iter().enumerate() {
+        let backlink = if linked.contains(&index) {
+            format!("<a href=\"#ch{}-fnref{}\">{}</a>", number, index + 1, marker)
+        } else {
+            marker.to_string()
+        };
+        html.push(format!(
+            "<aside epub:type=\"footnote\" id=\"ch{}-fn{}\"><p>{} {}</p></aside>",
+            number,
+            index + 1,
+            backlink,
+            html_escape::encode_text(text)
+        ));
+    }
+    Some(html.join("\n"))
+}
+
+// Render Markdown, pointing embedded images at their copy in the EPUB.
+// Other images are kept when they're remote; relative ones would be broken
+// references, so they're dropped or replaced with their alt text. When
+// embedding, images that couldn't be embedded get their alt text too.
+fn markdown_to_html(content: &str, images: &EmbeddedImages, handling: MarkdownImages) -> String {
+    use pulldown_cmark::{Event, Tag, TagEnd};
+
+    let mut events = Vec::new();
+    // Alt text of the broken image being replaced
+    let mut alt_text: Option<String> = None;
+    for event in pulldown_cmark::Parser::new(content) {
+        if let Some(alt) = &mut alt_text {
+            match event {
+                Event::End(TagEnd::Image) => {
+                    let alt = alt_text.take().unwrap_or_default();
+                    if handling != MarkdownImages::Drop && !alt.is_empty() {
+                        events.push(Event::Text(alt.into()));
+                    }
+                }
+                Event::Text(text) | Event::Code(text) => alt.push_str(&text),
+                _ => {}
+            }
+            continue;
+        }
+        match event {
+            Event::Start(Tag::Image { link_type, dest_url, title, id }) => {
+                if let Some(image) = images.get(dest_url.as_ref()) {
+                    events.push(Event::Start(Tag::Image {
+                        link_type,
+                        dest_url: image.href.clone().into(),
+                        title,
+                        id,
+                    }));
+                } else if handling != MarkdownImages::Embed && crate::services::images::is_remote(&dest_url) {
+                    events.push(Event::Start(Tag::Image { link_type, dest_url, title, id }));
+                } else {
+                    alt_text = Some(String::new());
+                }
+            }
+            event => events.push(event),
+        }
+    }
+
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+    html
+}
+
+/// Strip unsafe tags and attributes from rendered HTML and return it as
+/// well-formed XHTML.
+pub fn sanitize(html: &str) -> String {
+    let mut builder = ammonia::Builder::default();
+    if let Some(tags) = ALLOWED_TAGS.as_ref() {
+        builder.tags(tags.iter().map(String::as_str).collect::<HashSet<_>>());
+    }
+    let cleaned = builder.clean(html).to_string();
+
+    // The sanitizer serializes as HTML; EPUB content must be XHTML
+    VOID_ELEMENT
+        .replace_all(&cleaned, "<$1$2 />")
+        .replace("&nbsp;", "&#160;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SectionRole;
+
+    fn chapter(title: &str, content: &str) -> Chapter {
+        Chapter {
+            title: title.to_string(),
+            content: content.to_string(),
+            start_pos: 0,
+            end_pos: content.len(),
+            role: SectionRole::Chapter,
+            linear: true,
+        }
+    }
+
+    fn document(content: &str, options: &EpubOptions) -> String {
+        chapter_document(&chapter("Chapter 1", content), 1, options, &EmbeddedImages::default())
+    }
+
+    #[test]
+    fn scripts_and_event_handlers_are_stripped_from_markdown() {
+        let content = "# Notes\n\n<script>alert('hi')</script>\n\nA <a href=\"https://example.com/\" onclick=\"steal()\">link</a> \
+                       and <img src=\"https://example.com/a.png\" onerror=\"steal()\" alt=\"a\"> picture.\n\n\
+                       <p onmouseover=\"steal()\">Hover</p>";
+        let options = EpubOptions {
+            content_format: ContentFormat::Markdown,
+            ..Default::default()
+        };
+        let xhtml = document(content, &options);
+        assert!(!xhtml.contains("<script"), "{}", xhtml);
+        assert!(!xhtml.contains("alert"), "{}", xhtml);
+        for handler in ["onclick", "onerror", "onmouseover", "steal"] {
+            assert!(!xhtml.contains(handler), "{} in {}", handler, xhtml);
+        }
+        assert!(xhtml.contains(">link</a>"));
+        assert!(xhtml.contains("<img src=\"https://example.com/a.png\""));
+        assert!(xhtml.contains(">Hover</p>"));
+    }
+
+    #[test]
+    fn code_is_kept_verbatim_in_a_pre_block() {
+        let code = "fn main() {\n    if a < b && c > d {\n        println!(\"{}\", a);\n    }\n}";
+        let options = EpubOptions {
+            content_format: ContentFormat::Preformatted,
+            ..Default::default()
+        };
+        let xhtml = document(code, &options);
+        let escaped = "fn main() {\n    if a &lt; b &amp;&amp; c &gt; d {\n        println!(\"{}\", a);\n    }\n}";
+        assert!(xhtml.contains(&format!("<pre style=\"white-space: pre-wrap\">{}</pre>", escaped)), "{}", xhtml);
+
+        let options = EpubOptions {
+            content_format: ContentFormat::Markdown,
+            ..Default::default()
+        };
+        let xhtml = document(&format!("Some prose.\n\n```rust\n{}\n```\n", code), &options);
+        assert!(xhtml.contains("<p>Some prose.</p>"));
+        let pre = &xhtml[xhtml.find("<pre>").expect("a pre block")..];
+        assert!(pre.contains(escaped), "{}", xhtml);
+    }
+
+    #[test]
+    fn the_stylesheet_follows_the_paragraph_style() {
+        let english = [chapter("Chapter 1", "It was a dark and stormy night.")];
+        let chinese = [chapter("第一章", "那是一个风雨交加的夜晚，他独自走在路上。")];
+        let indent = "p { margin: 0; text-indent: 2em; }\n";
+        let spaced = "p { margin: 0 0 1em 0; text-indent: 0; }\n";
+
+        assert_eq!(stylesheet(ParagraphStyle::Indent, &english), indent);
+        assert_eq!(stylesheet(ParagraphStyle::Spaced, &chinese), spaced);
+        assert_eq!(stylesheet(ParagraphStyle::Auto, &english), spaced);
+        assert_eq!(stylesheet(ParagraphStyle::Auto, &chinese), indent);
+    }
+
+    #[test]
+    fn footnote_markers_link_to_their_footnotes_and_back() {
+        let options = EpubOptions {
+            footnotes: true,
+            ..Default::default()
+        };
+        let body = chapter_body("The claim[1] is disputed.\n\n[1] See the appendix.", 3, &options, &EmbeddedImages::default());
+        assert_eq!(
+            body,
+            "<p>The claim<a epub:type=\"noteref\" href=\"#ch3-fn1\" id=\"ch3-fnref1\">[1]</a> is disputed.</p>\n\
+             <aside epub:type=\"footnote\" id=\"ch3-fn1\"><p><a href=\"#ch3-fnref1\">[1]</a> See the appendix.</p></aside>"
+        );
+
+        // Without footnote text, or with the option off, the marker stays plain text
+        let body = chapter_body("The claim[1] is disputed.", 3, &options, &EmbeddedImages::default());
+        assert_eq!(body, "<p>The claim[1] is disputed.</p>");
+        let body = chapter_body("A[1].\n\n[1] Note.", 3, &EpubOptions::default(), &EmbeddedImages::default());
+        assert_eq!(body, "<p>A[1].</p>\n<p>[1] Note.</p>");
+    }
+}
@@ -0,0 +1,251 @@
+//! Helpers shared by unit tests.
+
+use crate::services::storage::Storage;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Storage that keeps files in memory.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+#[async_trait::async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, name: &str, data: &[u8]) -> anyhow::Result<()> {
+        self.files.lock().unwrap().insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    async fn get(&self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.files.lock().unwrap().get(name).cloned())
+    }
+
+    async fn exists(&self, name: &str) -> anyhow::Result<bool> {
+        Ok(self.files.lock().unwrap().contains_key(name))
+    }
+
+    async fn delete(&self, name: &str) -> anyhow::Result<()> {
+        self.files.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.files.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn location(&self) -> String {
+        "memory".to_string()
+    }
+}
+
+/// A request received by a `MockServer`.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Path and query
+    pub path: String,
+    /// Headers by lowercase name
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("JSON request body")
+    }
+}
+
+type Responder = dyn Fn(&Request) -> (u16, Vec<u8>, Duration) + Send + Sync;
+
+/// An HTTP server on a local port that answers every request with the
+/// status and body its responder returns, and records the requests.
+pub struct MockServer {
+    /// `http://127.0.0.1:<port>`
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+    in_flight: Arc<InFlight>,
+}
+
+// Requests being handled now and at most, from a request arriving until its
+// response starts
+#[derive(Default)]
+struct InFlight {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl MockServer {
+    pub async fn start(respond: impl Fn(&Request) -> (u16, Vec<u8>) + Send + Sync + 'static) -> Self {
+        Self::start_delayed(move |request| {
+            let (status, body) = respond(request);
+            (status, body, Duration::ZERO)
+        })
+        .await
+    }
+
+    /// Like `start`, with the responder also giving how long to wait before
+    /// answering.
+    pub async fn start_delayed(
+        respond: impl Fn(&Request) -> (u16, Vec<u8>, Duration) + Send + Sync + 'static,
+    ) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind mock server");
+        let url = format!("http://{}", listener.local_addr().expect("mock server address"));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let respond: Arc<Responder> = Arc::new(respond);
+        let in_flight = Arc::new(InFlight::default());
+        let recorded = requests.clone();
+        let counted = in_flight.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let respond = respond.clone();
+                let recorded = recorded.clone();
+                let counted = counted.clone();
+                tokio::spawn(async move {
+                    let _ = serve(stream, respond, recorded, counted).await;
+                });
+            }
+        });
+        MockServer { url, requests, in_flight }
+    }
+
+    /// Requests received so far, in order.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The most requests that were being handled at the same time.
+    pub fn peak_in_flight(&self) -> usize {
+        self.in_flight.peak.load(Ordering::SeqCst)
+    }
+}
+
+// Read one request from the connection, answer it and close the connection
+async fn serve(
+    mut stream: tokio::net::TcpStream,
+    respond: Arc<Responder>,
+    recorded: Arc<Mutex<Vec<Request>>>,
+    in_flight: Arc<InFlight>,
+) -> std::io::Result<()> {
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 8192];
+    let header_end = loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        data.extend_from_slice(&buffer[..read]);
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+    let length: usize = headers.get("content-length").and_then(|value| value.parse().ok()).unwrap_or(0);
+    while data.len() < header_end + length {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        data.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = Request {
+        method,
+        path,
+        headers,
+        body: data[header_end..].to_vec(),
+    };
+    let current = in_flight.current.fetch_add(1, Ordering::SeqCst) + 1;
+    in_flight.peak.fetch_max(current, Ordering::SeqCst);
+    let (status, body, delay) = respond(&request);
+    let head_only = request.method == "HEAD";
+    recorded.lock().unwrap().push(request);
+    tokio::time::sleep(delay).await;
+    // Before answering, since the client may send its next request as soon
+    // as it has the response
+    in_flight.current.fetch_sub(1, Ordering::SeqCst);
+
+    let mut response = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )
+    .into_bytes();
+    if !head_only {
+        response.extend_from_slice(&body);
+    }
+    stream.write_all(&response).await?;
+    stream.shutdown().await
+}
+
+/// The files in an EPUB (or any zip), by path, as text.
+pub fn epub_files(epub: &[u8]) -> std::collections::BTreeMap<String, String> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(epub)).expect("EPUB is a zip");
+    (0..archive.len())
+        .map(|index| {
+            let mut file = archive.by_index(index).expect("zip entry");
+            let mut data = Vec::new();
+            std::io::Read::read_to_end(&mut file, &mut data).expect("read zip entry");
+            (file.name().to_string(), String::from_utf8_lossy(&data).to_string())
+        })
+        .collect()
+}
+
+/// A fresh, empty directory under the system temp dir.
+pub fn temp_dir() -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("duanzh-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).expect("create temp dir");
+    dir
+}
+
+/// A `multipart/form-data` request body, built by hand since the HTTP
+/// client is compiled without multipart support.
+#[derive(Default)]
+pub struct Form {
+    body: Vec<u8>,
+}
+
+const BOUNDARY: &str = "duanzh-test-boundary";
+
+impl Form {
+    pub fn text(self, name: &str, value: &str) -> Self {
+        self.part(&format!("name=\"{}\"", name), value.as_bytes())
+    }
+
+    pub fn file(self, name: &str, file_name: &str, data: &[u8]) -> Self {
+        self.part(&format!("name=\"{}\"; filename=\"{}\"", name, file_name), data)
+    }
+
+    fn part(mut self, disposition: &str, data: &[u8]) -> Self {
+        self.body.extend_from_slice(
+            format!("--{}\r\nContent-Disposition: form-data; {}\r\n\r\n", BOUNDARY, disposition).as_bytes(),
+        );
+        self.body.extend_from_slice(data);
+        self.body.extend_from_slice(b"\r\n");
+        self
+    }
+
+    /// POST the form to `url`.
+    pub async fn post(mut self, url: impl reqwest::IntoUrl) -> reqwest::Response {
+        self.body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+        reqwest::Client::new()
+            .post(url)
+            .header("Content-Type", format!("multipart/form-data; boundary={}", BOUNDARY))
+            .body(self.body)
+            .send()
+            .await
+            .expect("POST form")
+    }
+}
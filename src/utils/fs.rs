@@ -0,0 +1,87 @@
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+// Where generated files are stored, from OUTPUT_DIR (default: ./output)
+static OUTPUT_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    std::env::var("OUTPUT_DIR")
+        .ok()
+        .filter(|dir| !dir.trim().is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("./output"))
+});
+
+pub fn output_dir() -> &'static Path {
+    &OUTPUT_DIR
+}
+
+/// Create the output directory if needed and check that files can be
+/// written to it, so a misconfigured directory fails at startup rather than
+/// on the first upload.
+pub fn ensure_output_dir() -> Result<()> {
+    ensure_writable_dir(output_dir())
+}
+
+fn ensure_writable_dir(dir: &Path) -> Result<()> {
+    create_dir(dir)?;
+
+    let probe = dir.join(format!(".write-test-{}", std::process::id()));
+    std::fs::write(&probe, b"")
+        .with_context(|| format!("Output directory {} is not writable", dir.display()))?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
+fn create_dir(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create output directory {}", dir.display()))
+}
+
+/// Write `data` to `path` via a temporary file that is flushed and renamed
+/// into place, so readers never see a partially written file. The parent
+/// directory is created if needed.
+pub fn write_atomic(path: impl AsRef<Path>, data: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        create_dir(parent)?;
+    }
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let result = (|| {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result.with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_unusable_output_dir_is_reported_with_its_path_and_cause() {
+        // A file where a directory is expected fails even for root
+        let parent = crate::test_support::temp_dir();
+        let blocker = parent.join("not-a-dir");
+        std::fs::write(&blocker, b"").unwrap();
+        let dir = blocker.join("output");
+
+        let error = format!("{:#}", ensure_writable_dir(&dir).unwrap_err());
+        assert!(error.starts_with(&format!("Failed to create output directory {}: ", dir.display())), "{}", error);
+
+        let path = dir.join("book.epub");
+        let error = format!("{:#}", write_atomic(&path, b"epub").unwrap_err());
+        assert!(error.starts_with(&format!("Failed to create output directory {}: ", dir.display())), "{}", error);
+
+        ensure_writable_dir(&parent.join("output")).unwrap();
+        let _ = std::fs::remove_dir_all(parent);
+    }
+}
@@ -0,0 +1,114 @@
+use regex::Regex;
+use std::sync::LazyLock;
+
+// Markup that says the upload is an HTML document rather than plain text
+static HTML_DOCUMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^\s*(<!doctype\s+html|<html[\s>])|<(body|h1|h2)[\s>]").expect("valid HTML document regex")
+});
+
+// Elements whose content is never part of the text
+static HIDDEN_ELEMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)<(head|script|style|noscript|template)\b[^>]*>.*?</(head|script|style|noscript|template)\s*>")
+        .expect("valid hidden element regex")
+});
+
+// A top-level heading, which starts a chapter
+static HEADING: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)<h[12]\b[^>]*>(.*?)</h[12]\s*>").expect("valid heading regex")
+});
+
+// Tags that separate blocks of text (paragraphs, list items, line breaks)
+static BLOCK_BOUNDARY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)<br\s*/?>|</?(p|div|li|ul|ol|dl|dt|dd|blockquote|section|article|aside|header|footer|h[3-6]|pre|table|tr|hr)\b[^>]*>")
+        .expect("valid block boundary regex")
+});
+
+/// Plain text extracted from an HTML upload.
+#[derive(Debug, Clone)]
+pub struct HtmlText {
+    pub text: String,
+    /// 1-based lines of `text` holding an `<h1>` or `<h2>` heading
+    pub heading_lines: Vec<usize>,
+}
+
+/// Whether decoded upload text looks like an HTML document.
+pub fn looks_like_html(text: &str) -> bool {
+    text.trim_start().starts_with('<') && HTML_DOCUMENT.is_match(text)
+}
+
+/// Convert an HTML document to plain text: each `<h1>`/`<h2>` heading on a
+/// line of its own, blocks separated by blank lines, all other markup
+/// removed and entities decoded.
+pub fn html_to_text(html: &str) -> HtmlText {
+    let html = HIDDEN_ELEMENT.replace_all(html, "");
+    let mut text = String::new();
+    let mut heading_lines = Vec::new();
+    // Lines in `text` so far, kept as it grows rather than recounted
+    let mut line_count = 0;
+    let mut last = 0;
+
+    for heading in HEADING.captures_iter(&html) {
+        let whole = heading.get(0).expect("match has a whole group");
+        line_count += push_block(&mut text, &fragment_text(&html[last..whole.start()]));
+        let title = inline_text(&heading[1]);
+        if !title.is_empty() {
+            line_count += push_block(&mut text, &title);
+            heading_lines.push(line_count);
+        }
+        last = whole.end();
+    }
+    push_block(&mut text, &fragment_text(&html[last..]));
+    text.push('\n');
+
+    HtmlText { text, heading_lines }
+}
+
+/// Append a block of text, separated from the previous one by a blank line.
+/// Returns how many lines that added.
+fn push_block(text: &mut String, block: &str) -> usize {
+    if block.is_empty() {
+        return 0;
+    }
+    let mut added = 1;
+    if !text.is_empty() {
+        text.push_str("\n\n");
+        added += 1;
+    }
+    text.push_str(block);
+    added + block.matches('\n').count()
+}
+
+/// Text of a run of HTML blocks, one paragraph per block.
+fn fragment_text(html: &str) -> String {
+    let separated = BLOCK_BOUNDARY.replace_all(html, "\n\n");
+    separated
+        .split("\n\n")
+        .map(inline_text)
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Text of inline HTML: tags removed, entities decoded, whitespace collapsed.
+fn inline_text(html: &str) -> String {
+    let stripped = ammonia::Builder::empty().clean(html).to_string();
+    let decoded = html_escape::decode_html_entities(&stripped);
+    decoded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heading_lines_point_at_the_headings() {
+        let html = "<html><body><p>Front</p><p>matter</p><h1>One</h1><p>First</p>\
+                    <h2>Two</h2><h2> </h2><div>Second<br>line</div><h1>Three</h1></body></html>";
+        let extracted = html_to_text(html);
+        let lines: Vec<&str> = extracted.text.lines().collect();
+        assert_eq!(extracted.heading_lines, [5, 9, 15]);
+        for (line, title) in extracted.heading_lines.iter().zip(["One", "Two", "Three"]) {
+            assert_eq!(lines[line - 1], title);
+        }
+    }
+}
@@ -1 +1,7 @@
+pub mod fs;
+pub mod html;
+pub mod shutdown;
+pub mod text;
+pub mod title;
 
+pub use text::{decode_text, decode_text_lossy};
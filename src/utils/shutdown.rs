@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Notify;
+
+// Number of output files currently being written
+static IN_FLIGHT_WRITES: AtomicUsize = AtomicUsize::new(0);
+static WRITES_DONE: Notify = Notify::const_new();
+
+/// Held while an output file is being generated. Shutdown waits until every
+/// guard has been dropped so no half-written file is left behind.
+pub struct WriteGuard {
+    _private: (),
+}
+
+impl WriteGuard {
+    pub fn acquire() -> Self {
+        IN_FLIGHT_WRITES.fetch_add(1, Ordering::SeqCst);
+        WriteGuard { _private: () }
+    }
+}
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        if IN_FLIGHT_WRITES.fetch_sub(1, Ordering::SeqCst) == 1 {
+            WRITES_DONE.notify_waiters();
+        }
+    }
+}
+
+/// Number of output files being written right now.
+#[cfg(test)]
+pub fn in_flight_writes() -> usize {
+    IN_FLIGHT_WRITES.load(Ordering::SeqCst)
+}
+
+/// Wait until all in-progress output writes have completed.
+pub async fn wait_for_writes() {
+    loop {
+        // Register interest before checking so a concurrent drop can't be missed
+        let notified = WRITES_DONE.notified();
+        if IN_FLIGHT_WRITES.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Resolve when the process receives Ctrl+C or SIGTERM.
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
@@ -0,0 +1,456 @@
+use std::borrow::Cow;
+use std::string::FromUtf8Error;
+
+/// Maps byte positions in normalized text back to byte positions in the
+/// original upload. Each anchor records where a run of unchanged bytes starts
+/// in both texts; positions between anchors are offset linearly.
+#[derive(Debug, Clone, Default)]
+pub struct OffsetMap {
+    // (normalized offset, original offset), sorted by normalized offset
+    anchors: Vec<(usize, usize)>,
+}
+
+impl OffsetMap {
+    /// Record that normalized offset `normalized` corresponds to `original`.
+    pub fn push(&mut self, normalized: usize, original: usize) {
+        self.anchors.push((normalized, original));
+    }
+
+    /// Translate a normalized byte offset into an original byte offset.
+    pub fn original_offset(&self, normalized: usize) -> usize {
+        let idx = self.anchors.partition_point(|(n, _)| *n <= normalized);
+        if idx == 0 {
+            normalized
+        } else {
+            let (n, o) = self.anchors[idx - 1];
+            o + (normalized - n)
+        }
+    }
+}
+
+/// Uploaded text after decoding and normalization.
+#[derive(Debug, Clone)]
+pub struct DecodedText {
+    pub text: String,
+    pub offsets: OffsetMap,
+}
+
+/// Decode uploaded bytes as UTF-8, stripping a leading byte order mark.
+pub fn decode_text(bytes: &[u8]) -> Result<DecodedText, FromUtf8Error> {
+    let mut offsets = OffsetMap::default();
+
+    // Handle potential BOM (Byte Order Mark) in UTF-8 files
+    let body = if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        offsets.push(0, 3);
+        &bytes[3..]
+    } else {
+        bytes
+    };
+
+    let text = String::from_utf8(body.to_vec())?;
+    Ok(DecodedText { text, offsets })
+}
+
+/// Like `decode_text`, but replaces invalid UTF-8 sequences with U+FFFD
+/// instead of failing. Also returns how many sequences were replaced.
+pub fn decode_text_lossy(bytes: &[u8]) -> (DecodedText, usize) {
+    let mut offsets = OffsetMap::default();
+
+    let (body, mut original_pos) = if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        offsets.push(0, 3);
+        (&bytes[3..], 3)
+    } else {
+        (bytes, 0)
+    };
+
+    let mut text = String::with_capacity(body.len());
+    let mut replaced = 0;
+    for chunk in body.utf8_chunks() {
+        text.push_str(chunk.valid());
+        original_pos += chunk.valid().len();
+
+        let invalid = chunk.invalid();
+        if !invalid.is_empty() {
+            // The replacement character is usually longer than the bytes it
+            // stands for, so positions after it need a new anchor
+            text.push(char::REPLACEMENT_CHARACTER);
+            original_pos += invalid.len();
+            offsets.push(text.len(), original_pos);
+            replaced += 1;
+        }
+    }
+
+    (DecodedText { text, offsets }, replaced)
+}
+
+/// Decodes a UTF-8 upload chunk by chunk, holding back bytes of a character
+/// that is split across chunks. Produces the same text as `decode_text`.
+#[derive(Debug, Default)]
+pub struct Utf8ChunkDecoder {
+    pending: Vec<u8>,
+    started: bool,
+}
+
+impl Utf8ChunkDecoder {
+    /// Decode as much of the input received so far as possible. Returns
+    /// `None` once the input turns out not to be valid UTF-8.
+    pub fn push(&mut self, chunk: &[u8]) -> Option<String> {
+        self.pending.extend_from_slice(chunk);
+
+        // Wait until we can tell whether the input starts with a BOM
+        if !self.started {
+            if self.pending.len() < 3 && [0xEF, 0xBB, 0xBF].starts_with(&self.pending) {
+                return Some(String::new());
+            }
+            if self.pending.starts_with(&[0xEF, 0xBB, 0xBF]) {
+                self.pending.drain(..3);
+            }
+            self.started = true;
+        }
+
+        let valid_up_to = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            // An incomplete character at the end may be completed by the next chunk
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return None,
+        };
+
+        let rest = self.pending.split_off(valid_up_to);
+        let text = std::mem::replace(&mut self.pending, rest);
+        String::from_utf8(text).ok()
+    }
+
+    /// Signal the end of input. Returns `None` if the input ended in the
+    /// middle of a character.
+    pub fn finish(mut self) -> Option<String> {
+        let text = self.push(&[])?;
+        if self.pending.is_empty() || self.pending == [0xEF, 0xBB, 0xBF] {
+            Some(text)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether most of the letters in the text are Chinese, Japanese or Korean.
+/// Only the start of long texts is examined.
+pub fn is_mostly_cjk(text: impl Iterator<Item = char>) -> bool {
+    let (mut cjk, mut letters) = (0usize, 0usize);
+    for c in text.filter(|c| c.is_alphabetic()).take(10_000) {
+        letters += 1;
+        if is_cjk(c) {
+            cjk += 1;
+        }
+    }
+    cjk * 2 > letters
+}
+
+/// Fraction of the characters in the text that are printable or ordinary
+/// whitespace. Control characters, replacement characters from invalid
+/// UTF-8 and private-use characters count against it. Empty text is fully
+/// printable.
+pub fn printable_ratio(text: &str) -> f64 {
+    let (mut printable, mut total) = (0usize, 0usize);
+    for c in text.chars() {
+        total += 1;
+        let unprintable = (c.is_control() && !c.is_whitespace())
+            || c == char::REPLACEMENT_CHARACTER
+            || matches!(c, '\u{E000}'..='\u{F8FF}');
+        if !unprintable {
+            printable += 1;
+        }
+    }
+    if total == 0 { 1.0 } else { printable as f64 / total as f64 }
+}
+
+/// Remove control characters (other than tabs and line breaks) and invisible
+/// formatting characters such as zero-width spaces and soft hyphens, which
+/// scraped text often carries. Zero-width joiners are kept since some
+/// scripts and emoji need them.
+pub fn strip_invisible(text: &str) -> Cow<'_, str> {
+    let is_invisible = |c: char| {
+        (c.is_control() && !matches!(c, '\t' | '\n' | '\r'))
+            || matches!(c, '\u{00AD}' | '\u{180E}' | '\u{200B}' | '\u{2060}' | '\u{FEFF}')
+    };
+    if text.contains(is_invisible) {
+        Cow::Owned(text.chars().filter(|c| !is_invisible(*c)).collect())
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Replace curly quotes, dashes, ellipses and non-breaking spaces with
+/// their plain ASCII forms. Quotes, dashes and ellipses next to Chinese,
+/// Japanese or Korean text are CJK punctuation and are left alone.
+pub fn normalize_punctuation(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut normalized = String::with_capacity(text.len());
+    for (i, &c) in chars.iter().enumerate() {
+        let Some(replacement) = ascii_punctuation(c) else {
+            normalized.push(c);
+            continue;
+        };
+        // The nearest characters that aren't spaces or more punctuation
+        let is_context = |c: &&char| !c.is_whitespace() && ascii_punctuation(**c).is_none();
+        let before = chars[..i].iter().rev().find(is_context);
+        let after = chars[i + 1..].iter().find(is_context);
+        let in_cjk = [before, after].into_iter().flatten().any(|c| is_cjk(*c) || is_cjk_punctuation(*c));
+        if in_cjk && !matches!(c, '\u{00A0}' | '\u{2007}' | '\u{202F}') {
+            normalized.push(c);
+        } else {
+            normalized.push_str(replacement);
+        }
+    }
+    normalized
+}
+
+/// Collapse runs of blank (or whitespace-only) lines into a single
+/// paragraph break. Scene break lines such as `***`, `* * *` or `§` become
+/// paragraphs of their own, so they survive even when no blank line
+/// separates them from the text around them.
+pub fn normalize_spacing(text: &str) -> String {
+    let mut paragraphs: Vec<String> = Vec::new();
+    let mut lines: Vec<&str> = Vec::new();
+    let flush = |lines: &mut Vec<&str>, paragraphs: &mut Vec<String>| {
+        if !lines.is_empty() {
+            paragraphs.push(lines.join("\n"));
+            lines.clear();
+        }
+    };
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            flush(&mut lines, &mut paragraphs);
+        } else if is_scene_break(line) {
+            flush(&mut lines, &mut paragraphs);
+            paragraphs.push(line.trim().to_string());
+        } else {
+            lines.push(line.trim_end());
+        }
+    }
+    flush(&mut lines, &mut paragraphs);
+    paragraphs.join("\n\n")
+}
+
+/// Whether a line is a scene break: a short run of symbols like `***`,
+/// `# # #`, `~~~`, `———` or `◇◇◇`.
+pub fn is_scene_break(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty()
+        && line.chars().count() <= 12
+        && line
+            .chars()
+            .all(|c| c.is_whitespace() || "*#~-=_§·•—–◇◆○●☆★".contains(c))
+}
+
+/// The longest run of whole sentences at the start of `text` that fits in
+/// `max_chars` characters. A first sentence that is too long on its own is
+/// cut at a word boundary instead.
+pub fn head_excerpt(text: &str, max_chars: usize) -> &str {
+    let spans = sentence_spans(text);
+    let Some(first) = spans.first() else {
+        return "";
+    };
+    let fitting = spans
+        .iter()
+        .take_while(|span| text[first.start..span.end].chars().count() <= max_chars)
+        .last();
+    match fitting {
+        Some(last) => &text[first.start..last.end],
+        None => {
+            let cut = text[first.start..]
+                .char_indices()
+                .nth(max_chars)
+                .map_or(text.len(), |(offset, _)| first.start + offset);
+            let clipped = &text[first.start..cut];
+            // Don't end inside a Latin word
+            match clipped.rfind(char::is_whitespace) {
+                Some(space) if cut < text.len() && !clipped.ends_with(|c: char| is_cjk(c)) => {
+                    clipped[..space].trim_end()
+                }
+                _ => clipped,
+            }
+        }
+    }
+}
+
+/// The longest run of whole sentences at the end of `text` that fits in
+/// `max_chars` characters. A last sentence that is too long on its own is
+/// cut at a word boundary instead.
+pub fn tail_excerpt(text: &str, max_chars: usize) -> &str {
+    let spans = sentence_spans(text);
+    let Some(last) = spans.last() else {
+        return "";
+    };
+    let fitting = spans
+        .iter()
+        .rev()
+        .take_while(|span| text[span.start..last.end].chars().count() <= max_chars)
+        .last();
+    match fitting {
+        Some(first) => &text[first.start..last.end],
+        None => {
+            let sentence = &text[last.start..last.end];
+            let skip = sentence.chars().count().saturating_sub(max_chars);
+            let cut = sentence.char_indices().nth(skip).map_or(0, |(offset, _)| offset);
+            let clipped = &sentence[cut..];
+            // Don't start inside a Latin word
+            match clipped.find(char::is_whitespace) {
+                Some(space) if cut > 0 && !clipped.starts_with(|c: char| is_cjk(c)) => clipped[space..].trim_start(),
+                _ => clipped,
+            }
+        }
+    }
+}
+
+/// Whether `text` seems to pick up a sentence begun before it: it starts
+/// with a lowercase letter or with punctuation that continues a sentence.
+pub fn starts_mid_sentence(text: &str) -> bool {
+    text.trim_start()
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_lowercase() || matches!(c, ',' | ';' | ':' | '，' | '、' | '；' | '：'))
+}
+
+/// Whether `text` stops before the end of a sentence: its last character,
+/// ignoring closing quotes and brackets, isn't a sentence terminator.
+pub fn ends_mid_sentence(text: &str) -> bool {
+    text.trim_end()
+        .chars()
+        .rev()
+        .find(|c| !is_closing(*c) && !c.is_whitespace())
+        .is_some_and(|c| !matches!(c, '.' | '!' | '?' | '。' | '！' | '？' | '…'))
+}
+
+/// Byte ranges of the sentences in `text`, without surrounding whitespace.
+/// A sentence ends at a line break, after `。！？` (CJK, which needs no
+/// following space), or after `.!?` followed by whitespace and a word that
+/// doesn't start in lowercase (Latin, so "e.g. this" and "3.14" stay whole).
+/// Closing quotes and brackets after the terminator stay with its sentence.
+fn sentence_spans(text: &str) -> Vec<std::ops::Range<usize>> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut spans = Vec::new();
+    let mut start = 0;
+    let mut push = |start: usize, end: usize| {
+        let sentence = &text[start..end];
+        let trimmed = sentence.trim_start();
+        let from = start + sentence.len() - trimmed.len();
+        let to = from + trimmed.trim_end().len();
+        if from < to {
+            spans.push(from..to);
+        }
+    };
+
+    let mut i = 0;
+    while i < chars.len() {
+        let (offset, c) = chars[i];
+        if c == '\n' {
+            push(start, offset);
+            start = offset + 1;
+            i += 1;
+            continue;
+        }
+        let cjk = matches!(c, '。' | '！' | '？');
+        if !cjk && !matches!(c, '.' | '!' | '?') {
+            i += 1;
+            continue;
+        }
+        // Repeated terminators ("?!", "...") and closing quotes and brackets
+        let mut end = i + 1;
+        while end < chars.len() && (matches!(chars[end].1, '.' | '!' | '?' | '。' | '！' | '？') || is_closing(chars[end].1)) {
+            end += 1;
+        }
+        if !cjk {
+            let next_word = chars[end..].iter().map(|(_, c)| *c).find(|c| !c.is_whitespace() || *c == '\n');
+            let spaced = end == chars.len() || chars[end].1.is_whitespace();
+            if !spaced || next_word.is_some_and(char::is_lowercase) {
+                i = end;
+                continue;
+            }
+        }
+        let end_offset = chars.get(end).map_or(text.len(), |(offset, _)| *offset);
+        push(start, end_offset);
+        start = end_offset;
+        i = end;
+    }
+    push(start, text.len());
+    spans
+}
+
+fn is_closing(c: char) -> bool {
+    matches!(c, '"' | '\'' | '\u{201D}' | '\u{2019}' | ')' | ']' | '」' | '』' | '）' | '》' | '】')
+}
+
+fn ascii_punctuation(c: char) -> Option<&'static str> {
+    Some(match c {
+        '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' => "'",
+        '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{201F}' => "\"",
+        '\u{2013}' => "-",  // En dash
+        '\u{2014}' => "--", // Em dash
+        '\u{2026}' => "...",
+        '\u{00A0}' | '\u{2007}' | '\u{202F}' => " ", // Non-breaking spaces
+        _ => return None,
+    })
+}
+
+fn is_cjk_punctuation(c: char) -> bool {
+    matches!(c,
+        '\u{3000}'..='\u{303F}' // CJK symbols and punctuation
+        | '\u{FF00}'..='\u{FFEF}' // Fullwidth forms
+    )
+}
+
+pub fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}' // Hiragana, Katakana
+        | '\u{3400}'..='\u{4DBF}' // CJK Extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+        | '\u{AC00}'..='\u{D7AF}' // Hangul syllables
+        | '\u{F900}'..='\u{FAFF}' // CJK Compatibility Ideographs
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::chapterizer::{build_chapters, markers_at_lines};
+
+    #[test]
+    fn chapter_positions_map_back_to_the_original_bytes() {
+        let original = b"\xEF\xBB\xBFChapter 1\nBad \xFF\xFE bytes \xC3.\nChapter 2\nCaf\xC3\xA9 text.\n";
+        let (decoded, replaced) = decode_text_lossy(original);
+        assert_eq!(replaced, 3);
+
+        let chapters = build_chapters(&decoded.text, &markers_at_lines(&decoded.text, &[1, 3]).unwrap());
+        let original_range = |index: usize| {
+            let chapter = &chapters[index];
+            decoded.offsets.original_offset(chapter.start_pos)..decoded.offsets.original_offset(chapter.end_pos)
+        };
+        assert_eq!(&original[original_range(0)], b"Bad \xFF\xFE bytes \xC3.\n");
+        assert_eq!(&original[original_range(1)], "Café text.\n".as_bytes());
+    }
+
+    #[test]
+    fn excerpts_keep_whole_english_sentences() {
+        let text = "It was late. Dr. Watson, e.g. the doctor, waited 3.14 hours! \"Go,\" he said. Then silence.";
+        assert_eq!(head_excerpt(text, 12), "It was late.");
+        assert_eq!(head_excerpt(text, 70), "It was late. Dr. Watson, e.g. the doctor, waited 3.14 hours!");
+        assert_eq!(tail_excerpt(text, 30), "\"Go,\" he said. Then silence.");
+        assert_eq!(tail_excerpt(text, 20), "Then silence.");
+        // A sentence too long on its own is cut at a word boundary
+        assert_eq!(head_excerpt(text, 9), "It was");
+        assert_eq!(tail_excerpt(text, 9), "silence.");
+    }
+
+    #[test]
+    fn excerpts_keep_whole_chinese_sentences() {
+        let text = "他走了。「你去哪？」她问。没有人回答！夜很深";
+        assert_eq!(head_excerpt(text, 4), "他走了。");
+        assert_eq!(head_excerpt(text, 13), "他走了。「你去哪？」她问。");
+        assert_eq!(head_excerpt(text, 12), "他走了。「你去哪？」");
+        assert_eq!(tail_excerpt(text, 9), "没有人回答！夜很深");
+        assert_eq!(tail_excerpt(text, 4), "夜很深");
+        // Without spaces, a long sentence is cut at the character
+        assert_eq!(head_excerpt(text, 2), "他走");
+        assert_eq!(tail_excerpt(text, 2), "很深");
+    }
+}
@@ -0,0 +1,277 @@
+use crate::models::TocNumbering;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+// "chapter one - THE BEGINNING" style prefixes repeated in front of a title
+static REDUNDANT_PREFIX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^(?:chapter|chap\.?|section|part)\s+(?:\d+|[a-z]+)\s*[-:–—.]\s*(.+)$")
+        .expect("valid title prefix regex")
+});
+
+// Words kept lowercase in Title Case unless they start or end the title
+const SMALL_WORDS: &[&str] = &[
+    "a", "an", "and", "as", "at", "but", "by", "for", "in", "nor", "of", "on", "or", "the", "to",
+    "with",
+];
+
+/// Clean up a detected title: collapse whitespace, drop a redundant
+/// "Chapter N -" prefix and convert all-caps or all-lowercase Latin titles to
+/// Title Case. Titles in other scripts only get whitespace cleanup.
+pub fn normalize_title(title: &str) -> String {
+    let collapsed = title.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let stripped = match REDUNDANT_PREFIX.captures(&collapsed) {
+        Some(captures) => captures[1].trim().to_string(),
+        None => collapsed,
+    };
+
+    // Leave deliberately mixed-case titles ("iPhone Diaries") alone
+    let letters: Vec<char> = stripped.chars().filter(|c| c.is_ascii_alphabetic()).collect();
+    let uniform_case = !letters.is_empty()
+        && (letters.iter().all(char::is_ascii_uppercase) || letters.iter().all(char::is_ascii_lowercase));
+    if !uniform_case {
+        return stripped;
+    }
+
+    let words: Vec<&str> = stripped.split(' ').collect();
+    let last = words.len() - 1;
+    words
+        .iter()
+        .enumerate()
+        .map(|(i, word)| {
+            let lower = word.to_lowercase();
+            if i != 0 && i != last && SMALL_WORDS.contains(&lower.as_str()) {
+                lower
+            } else {
+                capitalize(&lower)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Prefix a TOC title with its chapter number, e.g. "3. Title" or
+/// "第三章 Title".
+pub fn numbered_title(number: usize, title: &str, numbering: TocNumbering) -> String {
+    match numbering {
+        TocNumbering::None => title.to_string(),
+        TocNumbering::Arabic => format!("{}. {}", number, title),
+        TocNumbering::Chinese => format!("第{}章 {}", chinese_numeral(number), title),
+    }
+}
+
+/// TOC titles for a list of chapter titles where every title used more
+/// than once gets its occurrence index appended, e.g. "Introduction (2)".
+/// Titles used once are unchanged.
+pub fn disambiguated_titles<'a>(titles: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let titles: Vec<&str> = titles.into_iter().collect();
+    let mut totals: HashMap<&str, usize> = HashMap::new();
+    for title in &titles {
+        *totals.entry(title).or_default() += 1;
+    }
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    titles
+        .iter()
+        .map(|title| {
+            if totals[title] == 1 {
+                return title.to_string();
+            }
+            let occurrence = seen.entry(title).or_default();
+            *occurrence += 1;
+            format!("{} ({})", title, occurrence)
+        })
+        .collect()
+}
+
+/// Read a number written with Chinese or Japanese numerals ("十二",
+/// "一百零五", "两千", "二〇", "参拾"). Returns `None` if `text` contains
+/// anything else.
+pub fn parse_chinese_numeral(text: &str) -> Option<u32> {
+    parse_numeral(text, |c| {
+        Some(match c {
+            '零' | '〇' => Numeral::Digit(0),
+            '一' | '壱' => Numeral::Digit(1),
+            '二' | '两' | '弐' => Numeral::Digit(2),
+            '三' | '参' => Numeral::Digit(3),
+            '四' => Numeral::Digit(4),
+            '五' => Numeral::Digit(5),
+            '六' => Numeral::Digit(6),
+            '七' => Numeral::Digit(7),
+            '八' => Numeral::Digit(8),
+            '九' => Numeral::Digit(9),
+            '十' | '拾' => Numeral::Unit(10),
+            '百' => Numeral::Unit(100),
+            '千' => Numeral::Unit(1000),
+            '万' | '萬' => Numeral::Myriad,
+            _ => return None,
+        })
+    })
+}
+
+/// Read a number written with Sino-Korean Hangul numerals ("삼", "십이",
+/// "이십일"). Returns `None` if `text` contains anything else.
+pub fn parse_korean_numeral(text: &str) -> Option<u32> {
+    parse_numeral(text, |c| {
+        Some(match c {
+            '영' | '공' => Numeral::Digit(0),
+            '일' => Numeral::Digit(1),
+            '이' => Numeral::Digit(2),
+            '삼' => Numeral::Digit(3),
+            '사' => Numeral::Digit(4),
+            '오' => Numeral::Digit(5),
+            '육' | '륙' => Numeral::Digit(6),
+            '칠' => Numeral::Digit(7),
+            '팔' => Numeral::Digit(8),
+            '구' => Numeral::Digit(9),
+            '십' => Numeral::Unit(10),
+            '백' => Numeral::Unit(100),
+            '천' => Numeral::Unit(1000),
+            '만' => Numeral::Myriad,
+            _ => return None,
+        })
+    })
+}
+
+/// One character of an East Asian numeral.
+enum Numeral {
+    Digit(u32),
+    /// Ten, a hundred or a thousand
+    Unit(u32),
+    /// Ten thousand, which multiplies everything before it
+    Myriad,
+}
+
+fn parse_numeral(text: &str, numeral: impl Fn(char) -> Option<Numeral>) -> Option<u32> {
+    let (mut total, mut section, mut digit) = (0u32, 0u32, 0u32);
+    // Positional digits without units, as in "二〇" for twenty
+    let mut positional = 0u32;
+    let mut has_unit = false;
+    for c in text.chars() {
+        match numeral(c)? {
+            Numeral::Digit(value) => {
+                positional = positional.checked_mul(10)?.checked_add(value)?;
+                digit = value;
+            }
+            Numeral::Unit(unit) => {
+                // "十二" means twelve: a bare unit counts as one of it
+                section = section.checked_add(digit.max(1).checked_mul(unit)?)?;
+                digit = 0;
+                has_unit = true;
+            }
+            Numeral::Myriad => {
+                let myriads = section.checked_add(digit)?.max(1);
+                total = total.checked_add(myriads.checked_mul(10_000)?)?;
+                section = 0;
+                digit = 0;
+                has_unit = true;
+            }
+        }
+    }
+    if text.is_empty() {
+        return None;
+    }
+    if has_unit {
+        total.checked_add(section)?.checked_add(digit)
+    } else {
+        Some(positional)
+    }
+}
+
+/// Write a number with Chinese numerals ("十二", "一百零五"). Numbers of
+/// ten thousand or more fall back to Arabic digits.
+pub fn chinese_numeral(number: usize) -> String {
+    const DIGITS: [char; 10] = ['零', '一', '二', '三', '四', '五', '六', '七', '八', '九'];
+    const UNITS: [(usize, char); 3] = [(1000, '千'), (100, '百'), (10, '十')];
+
+    if number == 0 || number >= 10_000 {
+        return number.to_string();
+    }
+
+    let mut result = String::new();
+    let mut rest = number;
+    let mut pending_zero = false;
+    for (unit, name) in UNITS {
+        let digit = rest / unit;
+        rest %= unit;
+        if digit == 0 {
+            pending_zero = !result.is_empty();
+            continue;
+        }
+        if pending_zero {
+            result.push('零');
+            pending_zero = false;
+        }
+        // "十二" rather than "一十二" when the number starts in the tens
+        if !(unit == 10 && digit == 1 && result.is_empty()) {
+            result.push(DIGITS[digit]);
+        }
+        result.push(name);
+    }
+    if rest > 0 {
+        if pending_zero {
+            result.push('零');
+        }
+        result.push(DIGITS[rest]);
+    }
+    result
+}
+
+/// Turn a title into a file name stem of lowercase ASCII letters, digits and
+/// dashes. Returns an empty string if nothing usable is left.
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(slug.trim_end_matches('-').len());
+    slug
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numerals_are_read() {
+        assert_eq!(parse_chinese_numeral("十二"), Some(12));
+        assert_eq!(parse_chinese_numeral("一百零五"), Some(105));
+        assert_eq!(parse_chinese_numeral("二〇"), Some(20));
+        assert_eq!(parse_chinese_numeral("参拾"), Some(30));
+        assert_eq!(parse_chinese_numeral("三万二千"), Some(32_000));
+        assert_eq!(parse_korean_numeral("이십일"), Some(21));
+        assert_eq!(parse_chinese_numeral("第三"), None);
+    }
+
+    #[test]
+    fn numerals_too_large_for_u32_are_rejected() {
+        assert_eq!(parse_chinese_numeral(&"九千万".repeat(48)), None);
+        assert_eq!(parse_korean_numeral(&"구천만".repeat(48)), None);
+        assert_eq!(parse_chinese_numeral(&"九".repeat(11)), None);
+    }
+
+    #[test]
+    fn messy_titles_are_normalized() {
+        assert_eq!(normalize_title("chapter one - THE BEGINNING"), "The Beginning");
+        assert_eq!(normalize_title("Chapter 2:   the   road   to the sea"), "The Road to the Sea");
+        assert_eq!(normalize_title("A TALE OF TWO CITIES"), "A Tale of Two Cities");
+        assert_eq!(normalize_title("iPhone Diaries"), "iPhone Diaries");
+        assert_eq!(normalize_title("  第三章   风起  "), "第三章 风起");
+        // Normalizing again changes nothing
+        for title in ["chapter one - THE BEGINNING", "A TALE OF TWO CITIES"] {
+            assert_eq!(normalize_title(&normalize_title(title)), normalize_title(title));
+        }
+    }
+}
@@ -65,6 +65,11 @@ document.addEventListener('DOMContentLoaded', function() {
             });
             
             if (!response.ok) {
+                // Prefer the server's error message when one is provided
+                const errorData = await response.json().catch(() => null);
+                if (errorData && errorData.error) {
+                    throw new Error(errorData.error);
+                }
                 throw new Error('服务器响应错误: ' + response.status);
             }
             